@@ -0,0 +1,166 @@
+use std::mem;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// The changes waiting for a notes' build worker. Changes that come in while we're building or
+/// waiting for our turn are merged into a single build.
+#[derive(Default)]
+pub struct BuildQueue {
+    pending: Mutex<Pending>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct Pending {
+    changes: Changes,
+    shutdown: bool,
+}
+
+/// What changed since our last build
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Changes {
+    pub paths: Vec<PathBuf>,
+    /// We lost track of what changed, so everything needs rebuilding
+    pub everything: bool,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty() && !self.everything
+    }
+
+    pub fn merge(&mut self, other: Changes) {
+        for path in other.paths {
+            if !self.paths.contains(&path) {
+                self.paths.push(path);
+            }
+        }
+        self.everything |= other.everything;
+    }
+}
+
+impl BuildQueue {
+    pub fn push<I>(&self, paths: I)
+    where
+        I: IntoIterator<Item = PathBuf>,
+    {
+        self.lock().changes.merge(Changes {
+            paths: paths.into_iter().collect(),
+            everything: false,
+        });
+
+        self.changed.notify_all();
+    }
+
+    /// Rebuild everything, like after our watcher missed some changes
+    pub fn push_everything(&self) {
+        self.lock().changes.everything = true;
+
+        self.changed.notify_all();
+    }
+
+    /// Wait for some changes, or `None` once we've shut down
+    pub fn wait(&self) -> Option<Changes> {
+        let mut pending = self.lock();
+        while pending.changes.is_empty() && !pending.shutdown {
+            pending = self
+                .changed
+                .wait(pending)
+                .expect("Our build queue lock should never be poisoned");
+        }
+
+        self.take(&mut pending)
+    }
+
+    /// Take any changes that came in since we last looked, or `None` once we've shut down
+    pub fn drain(&self) -> Option<Changes> {
+        self.take(&mut self.lock())
+    }
+
+    pub fn shutdown(&self) {
+        self.lock().shutdown = true;
+        self.changed.notify_all();
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.lock().shutdown
+    }
+
+    fn take(&self, pending: &mut Pending) -> Option<Changes> {
+        if pending.shutdown {
+            None
+        } else {
+            Some(mem::take(&mut pending.changes))
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Pending> {
+        self.pending
+            .lock()
+            .expect("Our build queue lock should never be poisoned")
+    }
+}
+
+/// Limits how many notes build at once across a runtime, so that something like a
+/// `git checkout` doesn't rebuild every open book at the same time
+#[derive(Clone)]
+pub struct BuildLimiter {
+    running: Arc<(Mutex<usize>, Condvar)>,
+    max_builds: usize,
+}
+
+/// Our turn to build, which lets the next notes build when it's dropped
+pub struct BuildPermit {
+    limiter: BuildLimiter,
+}
+
+impl BuildLimiter {
+    pub fn new(max_builds: usize) -> BuildLimiter {
+        BuildLimiter {
+            running: Default::default(),
+            // we always need to let someone build
+            max_builds: max_builds.max(1),
+        }
+    }
+
+    /// Wait for our turn to build, giving up if we're cancelled first
+    pub fn acquire<F>(&self, cancelled: F) -> Option<BuildPermit>
+    where
+        F: Fn() -> bool,
+    {
+        let (running, turn) = &*self.running;
+
+        let mut running = running
+            .lock()
+            .expect("Our build limiter lock should never be poisoned");
+        while *running >= self.max_builds {
+            if cancelled() {
+                return None;
+            }
+
+            // nothing tells us when we're cancelled, so check every so often
+            running = turn
+                .wait_timeout(running, Duration::from_millis(100))
+                .expect("Our build limiter lock should never be poisoned")
+                .0;
+        }
+
+        *running += 1;
+
+        Some(BuildPermit {
+            limiter: self.clone(),
+        })
+    }
+}
+
+impl Drop for BuildPermit {
+    fn drop(&mut self) {
+        let (running, turn) = &*self.limiter.running;
+
+        *running
+            .lock()
+            .expect("Our build limiter lock should never be poisoned") -= 1;
+        turn.notify_one();
+    }
+}
@@ -1,20 +1,313 @@
 use core::ptr;
-use std::ffi::CStr;
-use std::os::raw::c_char;
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::slice;
+use std::time::Duration;
 
-use crate::MdNotesRuntime;
+use crate::{
+    BuildIsolation, BuildOutput, EnvironmentSetup, MdNotesError, MdNotesRuntime,
+    MdNotesRuntimeConfig, NotesEvent, NotesId, PortPolicy, WatchMode,
+};
+
+/// The status of a call across the C interface. Anything other than `Ok` means the call failed
+/// and the details are available from `md_notes_last_error_message`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MdNotesStatus {
+    Ok = 0,
+    /// An argument couldn't be read, like a path that isn't valid UTF-8
+    InvalidArgument = 1,
+    /// A required pointer was null
+    NullPointer = 2,
+    /// The runtime panicked while handling the call
+    Panic = 3,
+    /// See `MdNotesError::Io`
+    Io = 4,
+    /// See `MdNotesError::Config`
+    Config = 5,
+    /// See `MdNotesError::Build`
+    Build = 6,
+    /// See `MdNotesError::Watch`
+    Watch = 7,
+    /// See `MdNotesError::Server`
+    Server = 8,
+    /// See `MdNotesError::InvalidNotesId`
+    InvalidNotesId = 9,
+}
+
+impl From<&MdNotesError> for MdNotesStatus {
+    fn from(e: &MdNotesError) -> Self {
+        match e {
+            MdNotesError::Io(_) => MdNotesStatus::Io,
+            MdNotesError::Config(_) => MdNotesStatus::Config,
+            MdNotesError::Build(_) | MdNotesError::Worker { .. } => MdNotesStatus::Build,
+            MdNotesError::Watch(_) => MdNotesStatus::Watch,
+            MdNotesError::Server(_) => MdNotesStatus::Server,
+            MdNotesError::InvalidNotesId(_) => MdNotesStatus::InvalidNotesId,
+        }
+    }
+}
+
+struct LastError {
+    status: MdNotesStatus,
+    message: CString,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<LastError>> = const { RefCell::new(None) };
+}
+
+fn set_last_error<S: Into<String>>(status: MdNotesStatus, message: S) -> MdNotesStatus {
+    let message = message.into();
+    error!("{:?}: {}", status, message);
+
+    // interior nul bytes can't be represented in a C string so strip them out
+    let message = CString::new(message.replace('\0', ""))
+        .expect("We removed all of the nul bytes from our message");
+
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(LastError { status, message }));
+
+    status
+}
+
+/// The status of the last failed call on this thread, or `Ok` if nothing has failed
+#[no_mangle]
+pub extern "C" fn md_notes_last_error_status() -> MdNotesStatus {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map(|error| error.status)
+            .unwrap_or(MdNotesStatus::Ok)
+    })
+}
+
+/// The message of the last failed call on this thread, or null if nothing has failed. The string
+/// is owned by the runtime and is only valid until the next failed call on this thread.
+#[no_mangle]
+pub extern "C" fn md_notes_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map(|error| error.message.as_ptr())
+            .unwrap_or_else(ptr::null)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn md_notes_clear_last_error() {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+}
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
 pub extern "C" fn md_notes_runtime_new() -> *mut MdNotesRuntime {
-    match MdNotesRuntime::new() {
-        Ok(runtime) => Box::into_raw(Box::new(runtime)),
-        Err(e) => {
-            error!("Error creating MdNotes Runtime{}", e);
+    ffi_call(|| new_runtime(MdNotesRuntimeConfig::default())).unwrap_or(ptr::null_mut())
+}
 
-            ptr::null_mut()
-        }
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MdNotesPortPolicy {
+    Random = 0,
+    Preferred = 1,
+    Persisted = 2,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MdNotesBuildOutput {
+    Book = 0,
+    Cache = 1,
+    Memory = 2,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MdNotesWatchMode {
+    Auto = 0,
+    Native = 1,
+    Polling = 2,
+}
+
+/// The C version of `MdNotesRuntimeConfig`. Start from `md_notes_runtime_options_default` so
+/// that new options keep their defaults. Null strings use the default for that option.
+#[repr(C)]
+pub struct MdNotesRuntimeOptions {
+    /// The IP address our server listens on
+    pub bind_address: *const c_char,
+    pub port_policy: MdNotesPortPolicy,
+    /// The port to try first with `Preferred`
+    pub port: u16,
+    /// The file our port is saved in with `Persisted`
+    pub port_file: *const c_char,
+    pub init_logger: bool,
+    /// Don't import the environment of our login shells
+    pub skip_environment: bool,
+    /// The login shells to import our environment from, in order
+    pub shells: *const *const c_char,
+    pub shells_len: usize,
+    pub broadcast_buffer: usize,
+    pub build_output: MdNotesBuildOutput,
+    /// The directory our books are built under with `Cache`
+    pub cache_dir: *const c_char,
+    pub max_concurrent_builds: usize,
+    /// The executable to run each build in with `build-worker`, or null to build in process
+    pub build_worker: *const c_char,
+    /// How long a build worker gets before it's killed
+    pub build_timeout_ms: u64,
+    pub watch_mode: MdNotesWatchMode,
+    /// How often polling watchers look for changes
+    pub poll_interval_ms: u64,
+}
+
+#[no_mangle]
+pub extern "C" fn md_notes_runtime_options_default() -> MdNotesRuntimeOptions {
+    let config = MdNotesRuntimeConfig::default();
+
+    MdNotesRuntimeOptions {
+        bind_address: ptr::null(),
+        port_policy: MdNotesPortPolicy::Persisted,
+        port: 0,
+        port_file: ptr::null(),
+        init_logger: config.init_logger,
+        skip_environment: config.environment == EnvironmentSetup::Skip,
+        shells: ptr::null(),
+        shells_len: 0,
+        broadcast_buffer: config.broadcast_buffer,
+        build_output: match config.build_output {
+            BuildOutput::Book => MdNotesBuildOutput::Book,
+            BuildOutput::Cache(_) => MdNotesBuildOutput::Cache,
+            BuildOutput::Memory => MdNotesBuildOutput::Memory,
+        },
+        cache_dir: ptr::null(),
+        max_concurrent_builds: config.max_concurrent_builds,
+        build_worker: ptr::null(),
+        build_timeout_ms: BuildIsolation::DEFAULT_TIMEOUT.as_millis() as u64,
+        watch_mode: match config.watch_mode {
+            WatchMode::Auto => MdNotesWatchMode::Auto,
+            WatchMode::Native => MdNotesWatchMode::Native,
+            WatchMode::Polling => MdNotesWatchMode::Polling,
+        },
+        poll_interval_ms: config.poll_interval.as_millis() as u64,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn md_notes_runtime_new_with_options(
+    options: *const MdNotesRuntimeOptions,
+) -> *mut MdNotesRuntime {
+    ffi_call(|| {
+        let options = options.as_ref().ok_or_else(|| {
+            (
+                MdNotesStatus::NullPointer,
+                "The runtime options are null".to_string(),
+            )
+        })?;
+
+        new_runtime(runtime_config(options)?)
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+fn new_runtime(config: MdNotesRuntimeConfig) -> FfiResult<*mut MdNotesRuntime> {
+    MdNotesRuntime::with_config(config)
+        .map(|runtime| Box::into_raw(Box::new(runtime)))
+        .map_err(|e| {
+            (
+                MdNotesStatus::from(&e),
+                format!("Error creating MdNotes Runtime: {}", e),
+            )
+        })
+}
+
+unsafe fn runtime_config(options: &MdNotesRuntimeOptions) -> FfiResult<MdNotesRuntimeConfig> {
+    let mut config = MdNotesRuntimeConfig::default();
+
+    if let Some(bind_address) = optional_str(options.bind_address, "bind address")? {
+        config.bind_address = bind_address.parse().map_err(|e| {
+            (
+                MdNotesStatus::InvalidArgument,
+                format!("Invalid bind address '{}': {}", bind_address, e),
+            )
+        })?;
+    }
+
+    config.port = match options.port_policy {
+        MdNotesPortPolicy::Random => PortPolicy::Random,
+        MdNotesPortPolicy::Preferred => PortPolicy::Preferred(options.port),
+        MdNotesPortPolicy::Persisted => match optional_str(options.port_file, "port file")? {
+            Some(port_file) => PortPolicy::Persisted(PathBuf::from(port_file)),
+            None => PortPolicy::default(),
+        },
+    };
+
+    config.init_logger = options.init_logger;
+
+    config.environment = if options.skip_environment {
+        EnvironmentSetup::Skip
+    } else if options.shells.is_null() {
+        EnvironmentSetup::default()
+    } else {
+        let shells = slice::from_raw_parts(options.shells, options.shells_len)
+            .iter()
+            .map(|shell| {
+                optional_str(*shell, "shell")?
+                    .map(String::from)
+                    .ok_or_else(|| (MdNotesStatus::NullPointer, "A shell is null".to_string()))
+            })
+            .collect::<FfiResult<Vec<_>>>()?;
+
+        EnvironmentSetup::LoginShells(shells)
+    };
+
+    config.broadcast_buffer = options.broadcast_buffer;
+
+    config.build_output = match options.build_output {
+        MdNotesBuildOutput::Book => BuildOutput::Book,
+        MdNotesBuildOutput::Cache => match optional_str(options.cache_dir, "cache dir")? {
+            Some(cache_dir) => BuildOutput::Cache(PathBuf::from(cache_dir)),
+            None => match BuildOutput::default() {
+                cache @ BuildOutput::Cache(_) => cache,
+                _ => {
+                    return Err((
+                        MdNotesStatus::InvalidArgument,
+                        "There's no default cache directory, so one is required".to_string(),
+                    ))
+                }
+            },
+        },
+        MdNotesBuildOutput::Memory => BuildOutput::Memory,
+    };
+
+    config.max_concurrent_builds = options.max_concurrent_builds;
+
+    if let Some(worker) = optional_str(options.build_worker, "build worker")? {
+        config.build_isolation = BuildIsolation::Process {
+            worker: PathBuf::from(worker),
+            timeout: Duration::from_millis(options.build_timeout_ms),
+        };
     }
+
+    config.watch_mode = match options.watch_mode {
+        MdNotesWatchMode::Auto => WatchMode::Auto,
+        MdNotesWatchMode::Native => WatchMode::Native,
+        MdNotesWatchMode::Polling => WatchMode::Polling,
+    };
+    config.poll_interval = Duration::from_millis(options.poll_interval_ms.max(1));
+
+    Ok(config)
+}
+
+/// Run a single build for a runtime with a `build_worker`, returning the exit code. Call this
+/// from `main` when your worker executable is started with `build-worker`. If the build panics
+/// the exit code is `MdNotesStatus::Panic` and the details are in our last error.
+#[no_mangle]
+pub extern "C" fn md_notes_run_build_worker() -> i32 {
+    ffi_call(|| Ok(crate::run_build_worker())).unwrap_or_else(|status| status as i32)
 }
 
 #[allow(clippy::missing_safety_doc)]
@@ -24,33 +317,282 @@ pub unsafe extern "C" fn md_notes_runtime_free(ptr: *mut MdNotesRuntime) {
         return;
     }
 
-    Box::from_raw(ptr);
+    let _ = ffi_call(|| {
+        drop(Box::from_raw(ptr));
+
+        Ok(())
+    });
 }
 
+/// Returns the port of our server, or 0 if it couldn't be found
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
 pub unsafe extern "C" fn md_notes_runtime_server_port(ptr: *mut MdNotesRuntime) -> u16 {
-    let runtime = &mut *ptr;
+    ffi_call(|| Ok(runtime_ref(ptr)?.server_port())).unwrap_or(0)
+}
 
-    runtime.server_port()
+/// The version of the C interface. Version 1 uses `u8` notes ids, version 2 adds the `_v2`
+/// functions that use the full width `u64` notes ids.
+pub const MD_NOTES_API_VERSION: u32 = 2;
+
+#[no_mangle]
+pub extern "C" fn md_notes_api_version() -> u32 {
+    MD_NOTES_API_VERSION
 }
 
+/// Returns the id of the opened notes, or 0 if they couldn't be opened. Notes ids that don't fit
+/// in a `u8` can't be returned, so prefer `md_notes_runtime_open_notes_v2`.
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
 pub unsafe extern "C" fn md_notes_runtime_open_notes(
     ptr: *mut MdNotesRuntime,
     raw_path: *const c_char,
 ) -> u8 {
-    let runtime = &mut *ptr;
-    let path = CStr::from_ptr(raw_path).to_str().unwrap().to_string();
+    ffi_call(|| {
+        let runtime = runtime_ref(ptr)?;
+        let notes_id = open_notes(runtime, raw_path)?;
 
-    runtime.open_notes(path.into())
+        u8::try_from(notes_id).or_else(|_| {
+            // we can't hand these notes back so don't leave them open
+            runtime.close_notes(notes_id).map_err(notes_error)?;
+
+            Err((
+                MdNotesStatus::InvalidNotesId,
+                format!(
+                    "Notes id {} doesn't fit in the v1 interface, use md_notes_runtime_open_notes_v2",
+                    notes_id
+                ),
+            ))
+        })
+    })
+    .unwrap_or(0)
 }
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn md_notes_runtime_close_notes(ptr: *mut MdNotesRuntime, notes_id: u8) {
-    let runtime = &mut *ptr;
+pub unsafe extern "C" fn md_notes_runtime_close_notes(
+    ptr: *mut MdNotesRuntime,
+    notes_id: u8,
+) -> MdNotesStatus {
+    md_notes_runtime_close_notes_v2(ptr, notes_id.into())
+}
+
+/// Open the notes at `raw_path` and write their id into `notes_id`
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn md_notes_runtime_open_notes_v2(
+    ptr: *mut MdNotesRuntime,
+    raw_path: *const c_char,
+    notes_id: *mut NotesId,
+) -> MdNotesStatus {
+    ffi_call(|| {
+        let runtime = runtime_ref(ptr)?;
+
+        if notes_id.is_null() {
+            return Err((
+                MdNotesStatus::NullPointer,
+                "The notes id output is null".to_string(),
+            ));
+        }
+
+        *notes_id = open_notes(runtime, raw_path)?;
+
+        Ok(())
+    })
+    .err()
+    .unwrap_or(MdNotesStatus::Ok)
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn md_notes_runtime_close_notes_v2(
+    ptr: *mut MdNotesRuntime,
+    notes_id: NotesId,
+) -> MdNotesStatus {
+    ffi_call(|| runtime_ref(ptr)?.close_notes(notes_id).map_err(notes_error))
+        .err()
+        .unwrap_or(MdNotesStatus::Ok)
+}
+
+/// Returns the slug the notes are served under, or null if the notes id is invalid. The string
+/// must be freed with `md_notes_string_free`.
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn md_notes_runtime_notes_slug(
+    ptr: *mut MdNotesRuntime,
+    notes_id: NotesId,
+) -> *mut c_char {
+    ffi_call(|| {
+        let slug = runtime_ref(ptr)?
+            .notes_slug(notes_id)
+            .ok_or_else(|| notes_error(MdNotesError::InvalidNotesId(notes_id)))?;
+
+        CString::new(slug)
+            .map(CString::into_raw)
+            .map_err(|e| (MdNotesStatus::InvalidArgument, e.to_string()))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn md_notes_string_free(ptr: *mut c_char) {
+    if ptr.is_null() {
+        return;
+    }
+
+    drop(CString::from_raw(ptr));
+}
+
+unsafe fn open_notes(runtime: &MdNotesRuntime, raw_path: *const c_char) -> FfiResult<NotesId> {
+    let path = optional_str(raw_path, "notes path")?.ok_or_else(|| {
+        (
+            MdNotesStatus::NullPointer,
+            "The notes path is null".to_string(),
+        )
+    })?;
+
+    runtime.open_notes(path.into()).map_err(notes_error)
+}
+
+/// Read a C string that may be null
+unsafe fn optional_str<'a>(raw: *const c_char, name: &str) -> FfiResult<Option<&'a str>> {
+    if raw.is_null() {
+        return Ok(None);
+    }
+
+    CStr::from_ptr(raw).to_str().map(Some).map_err(|e| {
+        (
+            MdNotesStatus::InvalidArgument,
+            format!("The {} isn't valid UTF-8: {}", name, e),
+        )
+    })
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MdNotesEventKind {
+    Loading = 0,
+    Built = 1,
+    BuildFailed = 2,
+    Closed = 3,
+    WatchFailed = 4,
+    Orphaned = 5,
+}
+
+/// A notes lifecycle event. `duration_ms` is only set for `Built` and `message` is only set for
+/// `BuildFailed` and `WatchFailed`, otherwise it is null. The event is only valid for the length of the callback.
+#[repr(C)]
+pub struct MdNotesEvent {
+    pub kind: MdNotesEventKind,
+    pub notes_id: NotesId,
+    pub duration_ms: u64,
+    pub message: *const c_char,
+}
+
+pub type MdNotesEventCallback = extern "C" fn(context: *mut c_void, event: *const MdNotesEvent);
+
+/// The context pointer is owned by the native side, we just hand it back to the callback
+struct CallbackContext(*mut c_void);
+
+unsafe impl Send for CallbackContext {}
+unsafe impl Sync for CallbackContext {}
+
+/// Register a callback for the lifecycle events of every notes, replacing any previous callback.
+/// Passing a null callback stops sending events. The callback is called from background threads,
+/// so the context must be safe to use from any thread.
+///
+/// Once this returns, or `md_notes_runtime_free` does, any calls to the previous callback have
+/// finished and its context can be freed. Each notes' events arrive in order, ending in `Closed`.
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn md_notes_runtime_set_event_callback(
+    ptr: *mut MdNotesRuntime,
+    callback: Option<MdNotesEventCallback>,
+    context: *mut c_void,
+) -> MdNotesStatus {
+    ffi_call(|| {
+        let runtime = runtime_ref(ptr)?;
+
+        match callback {
+            Some(callback) => {
+                let context = CallbackContext(context);
+
+                runtime.set_event_listener(move |notes_id, event| {
+                    let (kind, duration_ms, message) = match event {
+                        NotesEvent::Loading => (MdNotesEventKind::Loading, 0, None),
+                        NotesEvent::Built(duration) => {
+                            (MdNotesEventKind::Built, duration.as_millis() as u64, None)
+                        }
+                        NotesEvent::BuildFailed(message) => (
+                            MdNotesEventKind::BuildFailed,
+                            0,
+                            CString::new(message.replace('\0', "")).ok(),
+                        ),
+                        NotesEvent::WatchFailed(message) => (
+                            MdNotesEventKind::WatchFailed,
+                            0,
+                            CString::new(message.replace('\0', "")).ok(),
+                        ),
+                        NotesEvent::Orphaned => (MdNotesEventKind::Orphaned, 0, None),
+                        NotesEvent::Closed => (MdNotesEventKind::Closed, 0, None),
+                    };
+
+                    let c_event = MdNotesEvent {
+                        kind,
+                        notes_id,
+                        duration_ms,
+                        message: message.as_ref().map_or(ptr::null(), |m| m.as_ptr()),
+                    };
+
+                    callback(context.0, &c_event);
+                });
+            }
+            None => runtime.clear_event_listener(),
+        }
+
+        Ok(())
+    })
+    .err()
+    .unwrap_or(MdNotesStatus::Ok)
+}
+
+type FfiResult<T> = Result<T, (MdNotesStatus, String)>;
+
+fn notes_error(e: MdNotesError) -> (MdNotesStatus, String) {
+    (MdNotesStatus::from(&e), e.to_string())
+}
+
+/// Run a call from the C interface, recording any error or panic as our last error. Unwinding
+/// out of an `extern "C"` function is undefined behavior, so every exported function that can
+/// fail needs to go through here.
+fn ffi_call<T, F>(call: F) -> Result<T, MdNotesStatus>
+where
+    F: FnOnce() -> FfiResult<T>,
+{
+    match panic::catch_unwind(AssertUnwindSafe(call)) {
+        Ok(Ok(result)) => Ok(result),
+        Ok(Err((status, message))) => Err(set_last_error(status, message)),
+        Err(cause) => {
+            let message = cause
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| cause.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "Unknown panic".to_string());
+
+            Err(set_last_error(
+                MdNotesStatus::Panic,
+                format!("Panicked across the C interface: {}", message),
+            ))
+        }
+    }
+}
 
-    runtime.close_notes(notes_id);
+unsafe fn runtime_ref<'a>(ptr: *mut MdNotesRuntime) -> FfiResult<&'a MdNotesRuntime> {
+    ptr.as_ref().ok_or_else(|| {
+        (
+            MdNotesStatus::NullPointer,
+            "The MdNotes Runtime is null".to_string(),
+        )
+    })
 }
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use headers::{ETag, HeaderMapExt};
+use http::header::ETAG;
+use http::{HeaderMap, HeaderValue};
+use mime_guess::{mime, Mime};
+
+/// Files smaller than this don't shrink enough to be worth it
+const MIN_COMPRESSED_LEN: u64 = 1024;
+/// We compress whole files in memory, so really big ones are always sent as they are
+const MAX_COMPRESSED_LEN: u64 = 32 * 1024 * 1024;
+
+/// Brotli's quality from 0 to 11. Past this it gets much slower for very little.
+const BROTLI_QUALITY: u32 = 6;
+const BROTLI_WINDOW: u32 = 22;
+
+/// A `Content-Encoding` we can send
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// The encoding a client would most like from its `Accept-Encoding`, brotli if it likes
+    /// brotli and gzip just as much
+    pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+        let mut brotli = None;
+        let mut gzip = None;
+        let mut any = None;
+        for coding in accept_encoding.split(',') {
+            let mut params = coding.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|quality| quality.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            if name.eq_ignore_ascii_case("br") {
+                brotli = Some(quality);
+            } else if name.eq_ignore_ascii_case("gzip") || name.eq_ignore_ascii_case("x-gzip") {
+                gzip = Some(quality);
+            } else if name == "*" {
+                any = Some(quality);
+            }
+        }
+
+        let brotli = brotli.or(any).unwrap_or(0.0);
+        let gzip = gzip.or(any).unwrap_or(0.0);
+        if brotli > 0.0 && brotli >= gzip {
+            Some(Encoding::Brotli)
+        } else if gzip > 0.0 {
+            Some(Encoding::Gzip)
+        } else {
+            None
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    pub fn header_value(self) -> HeaderValue {
+        HeaderValue::from_static(self.name())
+    }
+
+    pub fn compress(self, contents: &[u8]) -> io::Result<Bytes> {
+        match self {
+            Encoding::Brotli => {
+                let mut compressed = vec![];
+                {
+                    let mut writer = brotli::CompressorWriter::new(
+                        &mut compressed,
+                        4096,
+                        BROTLI_QUALITY,
+                        BROTLI_WINDOW,
+                    );
+                    writer.write_all(contents)?;
+                }
+
+                Ok(Bytes::from(compressed))
+            }
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], Compression::default());
+                encoder.write_all(contents)?;
+
+                Ok(Bytes::from(encoder.finish()?))
+            }
+        }
+    }
+
+    /// Our encoded file is a different representation than the original, so it needs its own
+    /// etag
+    pub fn etag(self, etag: &ETag) -> ETag {
+        let mut headers = HeaderMap::new();
+        headers.typed_insert(etag.clone());
+        let tag = headers
+            .get(ETAG)
+            .and_then(|tag| tag.to_str().ok())
+            .expect("Our etag should always be a string");
+
+        format!("{}-{}\"", tag.trim_end_matches('"'), self.name())
+            .parse()
+            .expect("Our encoded etag should always be valid")
+    }
+}
+
+/// Whether a file is worth compressing, text is but images, fonts and media usually already are
+pub fn is_compressible(mime: &Mime, len: u64) -> bool {
+    let compressible_type = match (mime.type_(), mime.subtype()) {
+        (mime::TEXT, _) => true,
+        (mime::IMAGE, mime::SVG) => true,
+        (mime::APPLICATION, subtype) => {
+            matches!(
+                subtype.as_str(),
+                "javascript" | "json" | "xml" | "wasm" | "x-javascript"
+            ) || mime.suffix() == Some(mime::JSON)
+                || mime.suffix() == Some(mime::XML)
+        }
+        (mime::FONT, subtype) => matches!(subtype.as_str(), "ttf" | "otf"),
+        _ => false,
+    };
+
+    compressible_type && (MIN_COMPRESSED_LEN..=MAX_COMPRESSED_LEN).contains(&len)
+}
+
+/// Compressed files by their key, kept until the file they came from changes
+#[derive(Default)]
+pub struct CompressionCache {
+    files: Mutex<HashMap<(String, Encoding), (ETag, Bytes)>>,
+}
+
+impl CompressionCache {
+    /// Our file compressed with `encoding`, from our cache as long as it's the same version
+    pub fn get_or_compress<F>(
+        &self,
+        key: &str,
+        etag: &ETag,
+        encoding: Encoding,
+        contents: F,
+    ) -> io::Result<Bytes>
+    where
+        F: FnOnce() -> io::Result<Bytes>,
+    {
+        if let Some(compressed) = self.cached(key, etag, encoding) {
+            return Ok(compressed);
+        }
+
+        let compressed = encoding.compress(&contents()?)?;
+        self.lock().insert(
+            (key.to_string(), encoding),
+            (etag.clone(), compressed.clone()),
+        );
+
+        Ok(compressed)
+    }
+
+    /// Like `get_or_compress`, but reading and compressing on a blocking thread so that a big
+    /// file doesn't hold up everything else we serve
+    pub async fn get_or_compress_blocking<F>(
+        self: Arc<Self>,
+        key: String,
+        etag: ETag,
+        encoding: Encoding,
+        contents: F,
+    ) -> io::Result<Bytes>
+    where
+        F: FnOnce() -> io::Result<Bytes> + Send + 'static,
+    {
+        if let Some(compressed) = self.cached(&key, &etag, encoding) {
+            return Ok(compressed);
+        }
+
+        tokio::task::spawn_blocking(move || self.get_or_compress(&key, &etag, encoding, contents))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    fn cached(&self, key: &str, etag: &ETag, encoding: Encoding) -> Option<Bytes> {
+        match self.lock().get(&(key.to_string(), encoding)) {
+            Some((cached_etag, compressed)) if cached_etag == etag => Some(compressed.clone()),
+            _ => None,
+        }
+    }
+
+    /// Forget every encoding of the file with this key
+    pub fn remove(&self, key: &str) {
+        self.lock().retain(|(cached, _), _| cached != key);
+    }
+
+    /// Forget everything whose key starts with `prefix`
+    pub fn remove_prefix(&self, prefix: &str) {
+        self.lock().retain(|(key, _), _| !key.starts_with(prefix));
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<(String, Encoding), (ETag, Bytes)>> {
+        self.files
+            .lock()
+            .expect("Our compression cache lock should never be poisoned")
+    }
+}
@@ -0,0 +1,297 @@
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use std::{env, process};
+
+use crate::watcher::{NativeWatchers, WatcherFactory};
+use crate::{MdNotesError, MdNotesRuntime, NotesId};
+
+/// How we pick the port for our server. Browsers key `localStorage` by origin, so keeping the
+/// same port keeps mdbook's theme, sidebar and search state between restarts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PortPolicy {
+    /// Use any free port
+    Random,
+    /// Try this port first, falling back on any free port if it's taken
+    Preferred(u16),
+    /// Try the port saved in this file first. A port is only saved when the file doesn't have
+    /// one yet, so one that's taken for now, like by another instance, is still tried next time.
+    Persisted(PathBuf),
+}
+
+impl Default for PortPolicy {
+    /// The app's own persisted port
+    fn default() -> Self {
+        PortPolicy::persisted_for("app")
+    }
+}
+
+impl PortPolicy {
+    /// Persist our port in a file under the user's data directory that only this consumer of the
+    /// runtime uses, like the app or the CLI, so that they don't keep taking each other's port
+    pub fn persisted_for(consumer: &str) -> PortPolicy {
+        match dirs::data_dir() {
+            Some(data_dir) => {
+                PortPolicy::Persisted(data_dir.join("mdnotes").join(consumer).join("server-port"))
+            }
+            None => PortPolicy::Random,
+        }
+    }
+
+    /// The port we'd like to bind to, if any
+    pub fn preferred_port(&self) -> Option<u16> {
+        match self {
+            PortPolicy::Random => None,
+            PortPolicy::Preferred(port) => Some(*port),
+            PortPolicy::Persisted(port_file) => fs::read_to_string(port_file)
+                .ok()
+                .and_then(|raw_port| raw_port.trim().parse().ok()),
+        }
+    }
+
+    /// Remember the port we ended up with so that we can use it next time, unless we already
+    /// have one saved
+    pub fn remember_port(&self, port: u16) {
+        if let PortPolicy::Persisted(port_file) = self {
+            if self.preferred_port().is_some() {
+                return;
+            }
+
+            let result = port_file
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::write(port_file, port.to_string()));
+
+            if let Err(e) = result {
+                warn!("Couldn't save our server port to {:?}: {}", port_file, e);
+            }
+        }
+    }
+}
+
+/// How we import the user's shell environment, so that mdbook can find preprocessors and
+/// renderers on their `PATH`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EnvironmentSetup {
+    /// Don't touch our environment
+    Skip,
+    /// Load the environment of the first of these login shells that works, falling back on
+    /// adding Cargo to our `PATH`
+    LoginShells(Vec<String>),
+}
+
+impl Default for EnvironmentSetup {
+    fn default() -> Self {
+        EnvironmentSetup::LoginShells(vec!["zsh".into(), "bash".into()])
+    }
+}
+
+/// Where our notes are built. mdbook builds into the book by default, which clutters the
+/// user's repository and fails on read-only checkouts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuildOutput {
+    /// Use the book's own build directory
+    Book,
+    /// Build each book into its own directory under this one
+    Cache(PathBuf),
+    /// Build into a scratch directory, then serve the output from memory
+    Memory,
+}
+
+impl Default for BuildOutput {
+    fn default() -> Self {
+        match dirs::cache_dir() {
+            Some(cache_dir) => BuildOutput::Cache(cache_dir.join("mdnotes")),
+            None => BuildOutput::Memory,
+        }
+    }
+}
+
+impl BuildOutput {
+    /// The build directory to use instead of the book's own, if any
+    pub fn build_dir(&self, notes_id: NotesId, book_dir: &Path) -> Option<PathBuf> {
+        match self {
+            BuildOutput::Book => None,
+            BuildOutput::Cache(cache_dir) => {
+                // keyed by the book's path so that we reuse the same directory between runs
+                let hash = fnv1a(book_dir.to_string_lossy().as_bytes());
+                let name = book_dir
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+
+                Some(cache_dir.join(format!("{}-{:016x}", name, hash)))
+            }
+            BuildOutput::Memory => {
+                Some(env::temp_dir().join(format!("mdnotes-{}-{}", process::id(), notes_id)))
+            }
+        }
+    }
+}
+
+/// 64 bit FNV-1a, which unlike std's hashers gives the same hash on every Rust release
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    bytes.iter().fold(OFFSET_BASIS, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+    })
+}
+
+/// Where our books are built. mdbook runs preprocessors and renderers as external commands, so a
+/// hung or crashing one can stall or take down whatever is building the book.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum BuildIsolation {
+    /// Build on our own build threads
+    #[default]
+    InProcess,
+    /// Run each build in a `<worker> build-worker` child process, failing the build and killing
+    /// the worker if it takes longer than `timeout`
+    Process { worker: PathBuf, timeout: Duration },
+}
+
+impl BuildIsolation {
+    /// How long a worker gets to build a book unless told otherwise
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+}
+
+/// How we notice changes to our books. A book can pick its own with `watch = "<mode>"` in the
+/// `[mdnotes]` table of its `book.toml`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum WatchMode {
+    /// Use the platform's file events, falling back on polling for books where they can't be set
+    /// up or never arrive
+    #[default]
+    Auto,
+    Native,
+    /// Compare the size and modification time of every watched file at our poll interval
+    Polling,
+}
+
+impl WatchMode {
+    pub fn from_name(name: &str) -> Option<WatchMode> {
+        match name {
+            "auto" => Some(WatchMode::Auto),
+            "native" => Some(WatchMode::Native),
+            "polling" => Some(WatchMode::Polling),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MdNotesRuntimeConfig {
+    /// The address our server listens on
+    pub bind_address: IpAddr,
+    pub port: PortPolicy,
+    /// Initialize `env_logger` for the process, turn this off if you set up your own logger
+    pub init_logger: bool,
+    /// This is only applied by the first runtime in the process
+    pub environment: EnvironmentSetup,
+    /// How many reload messages can queue up for a slow websocket before it starts skipping them
+    pub broadcast_buffer: usize,
+    pub build_output: BuildOutput,
+    /// How many notes can build at once across the runtime
+    pub max_concurrent_builds: usize,
+    pub build_isolation: BuildIsolation,
+    /// Where our notes get their native file events from
+    pub watcher: Arc<dyn WatcherFactory>,
+    pub watch_mode: WatchMode,
+    /// How often polling watchers look for changes
+    pub poll_interval: Duration,
+}
+
+impl Default for MdNotesRuntimeConfig {
+    fn default() -> Self {
+        MdNotesRuntimeConfig {
+            bind_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: PortPolicy::default(),
+            init_logger: true,
+            environment: EnvironmentSetup::default(),
+            broadcast_buffer: 10,
+            build_output: BuildOutput::default(),
+            max_concurrent_builds: 2,
+            build_isolation: BuildIsolation::default(),
+            watcher: Arc::new(NativeWatchers),
+            watch_mode: WatchMode::default(),
+            poll_interval: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MdNotesRuntimeBuilder {
+    config: MdNotesRuntimeConfig,
+}
+
+impl MdNotesRuntimeBuilder {
+    pub fn new() -> MdNotesRuntimeBuilder {
+        MdNotesRuntimeBuilder::default()
+    }
+
+    pub fn bind_address<A: Into<IpAddr>>(mut self, bind_address: A) -> Self {
+        self.config.bind_address = bind_address.into();
+        self
+    }
+
+    pub fn port(mut self, port: PortPolicy) -> Self {
+        self.config.port = port;
+        self
+    }
+
+    pub fn init_logger(mut self, init_logger: bool) -> Self {
+        self.config.init_logger = init_logger;
+        self
+    }
+
+    pub fn environment(mut self, environment: EnvironmentSetup) -> Self {
+        self.config.environment = environment;
+        self
+    }
+
+    pub fn broadcast_buffer(mut self, broadcast_buffer: usize) -> Self {
+        self.config.broadcast_buffer = broadcast_buffer;
+        self
+    }
+
+    pub fn build_output(mut self, build_output: BuildOutput) -> Self {
+        self.config.build_output = build_output;
+        self
+    }
+
+    pub fn max_concurrent_builds(mut self, max_concurrent_builds: usize) -> Self {
+        self.config.max_concurrent_builds = max_concurrent_builds;
+        self
+    }
+
+    pub fn build_isolation(mut self, build_isolation: BuildIsolation) -> Self {
+        self.config.build_isolation = build_isolation;
+        self
+    }
+
+    pub fn watcher<W: WatcherFactory + 'static>(mut self, watcher: W) -> Self {
+        self.config.watcher = Arc::new(watcher);
+        self
+    }
+
+    pub fn watch_mode(mut self, watch_mode: WatchMode) -> Self {
+        self.config.watch_mode = watch_mode;
+        self
+    }
+
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.config.poll_interval = poll_interval;
+        self
+    }
+
+    pub fn config(&self) -> &MdNotesRuntimeConfig {
+        &self.config
+    }
+
+    pub fn build(self) -> Result<MdNotesRuntime, MdNotesError> {
+        MdNotesRuntime::with_config(self.config)
+    }
+}
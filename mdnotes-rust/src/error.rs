@@ -0,0 +1,99 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use mdbook::errors::Error as MDBookError;
+
+use crate::NotesId;
+
+#[derive(Debug)]
+pub enum MdNotesError {
+    /// Reading or writing something on disk failed
+    Io(io::Error),
+    /// The book couldn't be loaded or its `book.toml` is invalid
+    Config(MDBookError),
+    /// mdbook failed to render the book
+    Build(MDBookError),
+    /// Our build worker process failed the build, crashed or timed out
+    Worker {
+        message: String,
+        /// The worker's error chain, starting with the outermost error
+        causes: Vec<String>,
+    },
+    /// We couldn't watch the book for changes
+    Watch(notify::Error),
+    /// Our server or its runtime couldn't be started
+    Server(String),
+    /// The notes id doesn't refer to any open notes
+    InvalidNotesId(NotesId),
+}
+
+impl fmt::Display for MdNotesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MdNotesError::Io(e) => write!(f, "IO error: {}", e),
+            MdNotesError::Config(e) => {
+                write!(f, "Couldn't load the book: ")?;
+                write_chain(f, e)
+            }
+            MdNotesError::Build(e) => {
+                write!(f, "Couldn't build the book: ")?;
+                write_chain(f, e)
+            }
+            MdNotesError::Worker { message, .. } => write!(f, "{}", message),
+            MdNotesError::Watch(e) => write!(f, "Couldn't watch the file system: {}", e),
+            MdNotesError::Server(e) => write!(f, "Couldn't start the server: {}", e),
+            MdNotesError::InvalidNotesId(id) => write!(f, "Invalid notes id: {}", id),
+        }
+    }
+}
+
+impl MdNotesError {
+    /// The message of each error in our chain, starting with the outermost
+    pub fn causes(&self) -> Vec<String> {
+        match self {
+            MdNotesError::Config(e) | MdNotesError::Build(e) => {
+                e.iter().map(|cause| cause.to_string()).collect()
+            }
+            MdNotesError::Worker { causes, .. } => causes.clone(),
+            other => vec![other.to_string()],
+        }
+    }
+}
+
+/// mdbook errors wrap their causes, so print the whole chain to know what actually went wrong
+fn write_chain(f: &mut fmt::Formatter<'_>, error: &MDBookError) -> fmt::Result {
+    for (i, cause) in error.iter().enumerate() {
+        if i > 0 {
+            write!(f, ": ")?;
+        }
+        write!(f, "{}", cause)?;
+    }
+
+    Ok(())
+}
+
+impl Error for MdNotesError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MdNotesError::Io(e) => Some(e),
+            MdNotesError::Config(e) | MdNotesError::Build(e) => Some(e),
+            MdNotesError::Watch(e) => Some(e),
+            MdNotesError::Worker { .. }
+            | MdNotesError::Server(_)
+            | MdNotesError::InvalidNotesId(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for MdNotesError {
+    fn from(e: io::Error) -> Self {
+        MdNotesError::Io(e)
+    }
+}
+
+impl From<notify::Error> for MdNotesError {
+    fn from(e: notify::Error) -> Self {
+        MdNotesError::Watch(e)
+    }
+}
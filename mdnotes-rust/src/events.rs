@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, ThreadId};
+use std::time::Duration;
+
+use crate::NotesId;
+
+/// Lifecycle events for a set of open notes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NotesEvent {
+    /// The notes started (re)building
+    Loading,
+    /// The notes were built successfully in the given time
+    Built(Duration),
+    /// The notes couldn't be built
+    BuildFailed(String),
+    /// We couldn't keep watching the notes for changes, so they may be out of date
+    WatchFailed(String),
+    /// The notes' book directory was removed, so they won't build again until they're reopened
+    Orphaned,
+    /// The notes were closed and won't send any more events
+    Closed,
+}
+
+pub type NotesEventListener = Arc<dyn Fn(NotesId, &NotesEvent) + Send + Sync>;
+
+/// A shared slot for the listener of our notes events. Every notes gets a clone of this so that
+/// the listener can be swapped out while they're open.
+///
+/// Each notes' events are sent one at a time and in order. Notes only send events once they've
+/// been registered, and nothing after `Closed`.
+#[derive(Clone, Default)]
+pub struct NotesEvents {
+    shared: Arc<Shared>,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    /// Signalled whenever a call to our listener finishes
+    call_finished: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    listener: Option<NotesEventListener>,
+    /// Bumped whenever our listener changes, so we know which calls still use an old one
+    generation: u64,
+    notes: HashMap<NotesId, Registration>,
+    calls: Vec<Call>,
+    next_call: u64,
+}
+
+enum Registration {
+    /// The notes are still being opened, so their events wait until they're registered
+    Pending(Vec<NotesEvent>),
+    Open,
+}
+
+/// A call to our listener that's in progress
+struct Call {
+    id: u64,
+    notes_id: NotesId,
+    thread: ThreadId,
+    generation: u64,
+}
+
+impl NotesEvents {
+    /// Replace our listener, waiting for any calls to the old one to finish so that whatever it
+    /// uses can be freed once we return. Calls from within the listener don't wait on themselves.
+    pub fn set_listener(&self, listener: Option<NotesEventListener>) {
+        let mut state = self.lock();
+        state.listener = listener;
+        state.generation += 1;
+
+        let generation = state.generation;
+        let current = thread::current().id();
+        let _state = self.wait_while(state, |state| {
+            state
+                .calls
+                .iter()
+                .any(|call| call.generation < generation && call.thread != current)
+        });
+    }
+
+    /// Hold on to the events of notes that are being opened until they're registered
+    pub fn pending(&self, notes_id: NotesId) {
+        self.lock()
+            .notes
+            .insert(notes_id, Registration::Pending(vec![]));
+    }
+
+    /// Our notes were opened, so send what they've held on to and everything from now on
+    pub fn register(&self, notes_id: NotesId) {
+        let mut state = self.lock_for(notes_id);
+        if let Some(Registration::Pending(events)) = state.notes.get_mut(&notes_id) {
+            let events = mem::take(events);
+            state.notes.insert(notes_id, Registration::Open);
+
+            self.call(state, notes_id, events);
+        }
+    }
+
+    /// Our notes couldn't be opened, so send what they held on to, most likely why they failed
+    pub fn failed_to_open(&self, notes_id: NotesId) {
+        let mut state = self.lock_for(notes_id);
+        let registration = state.notes.remove(&notes_id);
+        if let Some(Registration::Pending(events)) = registration {
+            self.call(state, notes_id, events);
+        }
+    }
+
+    /// Our notes were closed. Registered notes send `Closed` after their last event, and notes
+    /// that were never registered drop their events without a word.
+    pub fn close(&self, notes_id: NotesId) {
+        let mut state = self.lock_for(notes_id);
+        if let Some(Registration::Open) = state.notes.remove(&notes_id) {
+            self.call(state, notes_id, vec![NotesEvent::Closed]);
+        }
+    }
+
+    pub fn emit(&self, notes_id: NotesId, event: NotesEvent) {
+        trace!("Notes event for {}: {:?}", notes_id, event);
+
+        let mut state = self.lock_for(notes_id);
+        match state.notes.get_mut(&notes_id) {
+            Some(Registration::Pending(events)) => events.push(event),
+            Some(Registration::Open) => self.call(state, notes_id, vec![event]),
+            // our notes were closed, so they've sent their last event
+            None => (),
+        }
+    }
+
+    /// Send our events to our listener outside of our lock, so that it can call back into us
+    fn call(&self, mut state: MutexGuard<'_, State>, notes_id: NotesId, events: Vec<NotesEvent>) {
+        let listener = match &state.listener {
+            Some(listener) if !events.is_empty() => listener.clone(),
+            _ => return,
+        };
+
+        let call = Call {
+            id: state.next_call,
+            notes_id,
+            thread: thread::current().id(),
+            generation: state.generation,
+        };
+        state.next_call += 1;
+        let call_id = call.id;
+        state.calls.push(call);
+        mem::drop(state);
+
+        // a panicking listener still finishes its call
+        let _finished = CallFinished {
+            shared: &self.shared,
+            call_id,
+        };
+        for event in &events {
+            listener(notes_id, event);
+        }
+    }
+
+    /// Lock our state once no other thread is sending events for our notes
+    fn lock_for(&self, notes_id: NotesId) -> MutexGuard<'_, State> {
+        let current = thread::current().id();
+
+        self.wait_while(self.lock(), |state| {
+            state
+                .calls
+                .iter()
+                .any(|call| call.notes_id == notes_id && call.thread != current)
+        })
+    }
+
+    fn wait_while<'a, F>(
+        &'a self,
+        state: MutexGuard<'a, State>,
+        condition: F,
+    ) -> MutexGuard<'a, State>
+    where
+        F: FnMut(&mut State) -> bool,
+    {
+        self.shared
+            .call_finished
+            .wait_while(state, condition)
+            .expect("Our events lock should never be poisoned")
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.shared
+            .state
+            .lock()
+            .expect("Our events lock should never be poisoned")
+    }
+}
+
+struct CallFinished<'a> {
+    shared: &'a Shared,
+    call_id: u64,
+}
+
+impl Drop for CallFinished<'_> {
+    fn drop(&mut self) {
+        // a listener that panicked poisons nothing, since we never call it under our lock
+        if let Ok(mut state) = self.shared.state.lock() {
+            state.calls.retain(|call| call.id != self.call_id);
+        }
+
+        self.shared.call_finished.notify_all();
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use notify::DebouncedEvent;
+
+use crate::watcher::{NotesWatcher, WatcherFactory};
+
+/// An in-memory watcher for tests. Nothing on disk is watched, events are only sent when they're
+/// injected, and then only to the notes watching that path. Injecting an event returns once every
+/// notes it was sent to has handled it, queueing any build it sets off.
+///
+/// Only available with the `test-util` feature.
+///
+/// ```no_run
+/// # use mdnotes::{FakeWatcher, MdNotesRuntime, WatchMode};
+/// let watcher = FakeWatcher::default();
+/// let runtime = MdNotesRuntime::builder()
+///     .watcher(watcher.clone())
+///     .watch_mode(WatchMode::Native)
+///     .build()
+///     .unwrap();
+/// let book_dir = std::path::PathBuf::from("my-book");
+/// runtime.open_notes(book_dir.clone()).unwrap();
+///
+/// watcher.write(book_dir.join("src/chapter.md"));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct FakeWatcher {
+    state: Arc<Mutex<FakeState>>,
+    /// Signalled whenever a notes has handled its events or stopped watching
+    handled: Arc<Condvar>,
+}
+
+#[derive(Debug, Default)]
+struct FakeState {
+    next_id: usize,
+    watchers: BTreeMap<usize, FakeWatch>,
+    /// Whether new watches fail, like on a filesystem our platform can't watch
+    failing: bool,
+}
+
+#[derive(Debug)]
+struct FakeWatch {
+    sender: Sender<DebouncedEvent>,
+    /// What we're watching and whether it's recursive
+    watched: BTreeMap<PathBuf, bool>,
+    /// How many events we've sent our notes, and how many of them they've handled
+    sent: u64,
+    handled: u64,
+}
+
+impl FakeWatch {
+    fn covers(&self, path: &Path) -> bool {
+        self.watched.iter().any(|(root, recursive)| {
+            path == root || (*recursive && path.starts_with(root)) || path.parent() == Some(root)
+        })
+    }
+}
+
+impl FakeWatcher {
+    pub fn create<P: Into<PathBuf>>(&self, path: P) {
+        let path = path.into();
+        self.send(&[&path], || DebouncedEvent::Create(path.clone()));
+    }
+
+    pub fn write<P: Into<PathBuf>>(&self, path: P) {
+        let path = path.into();
+        self.send(&[&path], || DebouncedEvent::Write(path.clone()));
+    }
+
+    pub fn rename<P: Into<PathBuf>, Q: Into<PathBuf>>(&self, from: P, to: Q) {
+        let (from, to) = (from.into(), to.into());
+        self.send(&[&from, &to], || {
+            DebouncedEvent::Rename(from.clone(), to.clone())
+        });
+    }
+
+    pub fn remove<P: Into<PathBuf>>(&self, path: P) {
+        let path = path.into();
+        self.send(&[&path], || DebouncedEvent::Remove(path.clone()));
+    }
+
+    /// Tell every watcher that we lost track of what changed
+    pub fn rescan(&self) {
+        self.send(&[], || DebouncedEvent::Rescan);
+    }
+
+    /// Tell every watcher that watching failed
+    pub fn error(&self, message: &str) {
+        self.send(&[], || {
+            DebouncedEvent::Error(notify::Error::Generic(message.into()), None)
+        });
+    }
+
+    /// Make every new watch fail with `Generic`
+    pub fn set_failing(&self, failing: bool) {
+        self.lock().failing = failing;
+    }
+
+    /// Whether any of our watchers would see a change to this path
+    pub fn is_watched<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.lock()
+            .watchers
+            .values()
+            .any(|watch| watch.covers(path.as_ref()))
+    }
+
+    /// Send an event to every watcher covering one of our paths, or all of them without paths,
+    /// then wait for them to handle it
+    fn send<F: Fn() -> DebouncedEvent>(&self, paths: &[&PathBuf], event: F) {
+        let mut state = self.lock();
+        let mut waiting = vec![];
+        for (id, watch) in state.watchers.iter_mut() {
+            if paths.is_empty() || paths.iter().any(|path| watch.covers(path)) {
+                // a watcher that's stopped listening is removed once it's dropped
+                if watch.sender.send(event()).is_ok() {
+                    watch.sent += 1;
+                    waiting.push((*id, watch.sent));
+                }
+            }
+        }
+
+        let _state = self
+            .handled
+            .wait_while(state, |state| {
+                waiting.iter().any(|(id, sent)| {
+                    state
+                        .watchers
+                        .get(id)
+                        .is_some_and(|watch| watch.handled < *sent)
+                })
+            })
+            .expect("Our fake watcher lock should never be poisoned");
+    }
+
+    fn lock(&self) -> MutexGuard<'_, FakeState> {
+        lock(&self.state)
+    }
+}
+
+fn lock(state: &Mutex<FakeState>) -> MutexGuard<'_, FakeState> {
+    state
+        .lock()
+        .expect("Our fake watcher lock should never be poisoned")
+}
+
+impl WatcherFactory for FakeWatcher {
+    fn start(
+        &self,
+        sender: Sender<DebouncedEvent>,
+    ) -> Result<Box<dyn NotesWatcher>, notify::Error> {
+        let mut state = self.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.watchers.insert(
+            id,
+            FakeWatch {
+                sender,
+                watched: BTreeMap::new(),
+                sent: 0,
+                handled: 0,
+            },
+        );
+
+        Ok(Box::new(FakeHandle {
+            id,
+            state: self.state.clone(),
+            handled: self.handled.clone(),
+        }))
+    }
+}
+
+/// A single notebook's view of our fake watcher
+struct FakeHandle {
+    id: usize,
+    state: Arc<Mutex<FakeState>>,
+    handled: Arc<Condvar>,
+}
+
+impl NotesWatcher for FakeHandle {
+    fn watch(&mut self, path: &Path, recursive: bool) -> Result<(), notify::Error> {
+        let mut state = lock(&self.state);
+        if state.failing {
+            return Err(notify::Error::Generic(
+                "Our fake watcher is failing".to_string(),
+            ));
+        }
+
+        if let Some(watch) = state.watchers.get_mut(&self.id) {
+            watch.watched.insert(path.to_path_buf(), recursive);
+        }
+
+        Ok(())
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Result<(), notify::Error> {
+        let mut state = lock(&self.state);
+        match state
+            .watchers
+            .get_mut(&self.id)
+            .and_then(|watch| watch.watched.remove(path))
+        {
+            Some(_) => Ok(()),
+            None => Err(notify::Error::WatchNotFound),
+        }
+    }
+
+    fn between_batches(&mut self, nothing_waiting: &mut dyn FnMut() -> bool) {
+        // we only send under our lock, so nothing can arrive while we check
+        let mut state = lock(&self.state);
+        if let Some(watch) = state.watchers.get_mut(&self.id) {
+            if watch.handled < watch.sent && nothing_waiting() {
+                watch.handled = watch.sent;
+                self.handled.notify_all();
+            }
+        }
+    }
+}
+
+impl Drop for FakeHandle {
+    fn drop(&mut self) {
+        lock(&self.state).watchers.remove(&self.id);
+        self.handled.notify_all();
+    }
+}
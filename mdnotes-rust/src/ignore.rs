@@ -0,0 +1,219 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use ignore::gitignore::{gitconfig_excludes_path, Gitignore, GitignoreBuilder};
+use ignore::Match;
+
+/// Our own ignore file, for things that should stay in git but never trigger a rebuild
+pub const MDNOTES_IGNORE: &str = ".mdnotesignore";
+
+/// Decides which changed paths are ignored, following git's rules: `.gitignore` files in every
+/// directory from the repository root down, `.git/info/exclude` and the global excludes file,
+/// with deeper and later rules overriding earlier ones. `.mdnotesignore` files are read alongside
+/// `.gitignore` and take precedence over it.
+///
+/// Ignore files are parsed once and only re-parsed when their size or modification time changes,
+/// which we check once for each batch of changes.
+pub struct IgnoreMatcher {
+    /// Where we start looking for ignore files, the repository root if our book is in one
+    root: PathBuf,
+    /// Our root with any symlinks resolved, some watchers report paths this way
+    canonical_root: Option<PathBuf>,
+    /// Ignore files that apply to the whole tree, from lowest to highest precedence
+    excludes: Vec<PathBuf>,
+    /// Our own output never triggers a build
+    build_dir: Option<PathBuf>,
+    cache: HashMap<PathBuf, CachedRules>,
+    /// The ignore files we've already checked for changes in this batch
+    checked: HashSet<PathBuf>,
+}
+
+struct CachedRules {
+    /// The size and modification time of the file when we parsed it, `None` if it's missing
+    stamp: Option<(u64, SystemTime)>,
+    rules: Option<Arc<Gitignore>>,
+}
+
+impl IgnoreMatcher {
+    pub fn new(book_dir: &Path, build_dir: Option<PathBuf>) -> IgnoreMatcher {
+        // worktrees and submodules have a `.git` file pointing at their git directory
+        let repo_root = book_dir
+            .ancestors()
+            .find(|dir| dir.join(".git").exists())
+            .map(Path::to_path_buf);
+
+        let mut excludes = vec![];
+        if let Some(global_excludes) = gitconfig_excludes_path() {
+            excludes.push(global_excludes);
+        }
+        if let Some(common_dir) = repo_root.as_deref().and_then(git_common_dir) {
+            excludes.push(common_dir.join("info").join("exclude"));
+        }
+
+        let root = repo_root.unwrap_or_else(|| book_dir.to_path_buf());
+
+        IgnoreMatcher {
+            canonical_root: fs::canonicalize(&root)
+                .ok()
+                .filter(|canonical| canonical != &root),
+            root,
+            excludes,
+            build_dir,
+            cache: HashMap::new(),
+            checked: HashSet::new(),
+        }
+    }
+
+    /// Filter our changed paths down to the ones that aren't ignored
+    pub fn unignored<I>(&mut self, paths: I) -> Vec<PathBuf>
+    where
+        I: IntoIterator<Item = PathBuf>,
+    {
+        // ignore files can only have changed since our last batch
+        self.checked.clear();
+
+        paths
+            .into_iter()
+            .filter(|path| !self.is_ignored(path))
+            .collect()
+    }
+
+    pub fn is_ignored(&mut self, path: &Path) -> bool {
+        if let Some(build_dir) = &self.build_dir {
+            if path.starts_with(build_dir) {
+                return true;
+            }
+        }
+
+        let relative = match path.strip_prefix(&self.root).or_else(|e| {
+            self.canonical_root
+                .as_ref()
+                .map_or(Err(e), |canonical_root| path.strip_prefix(canonical_root))
+        }) {
+            Ok(relative) => relative.to_path_buf(),
+            // git has nothing to say about anything outside of its repository
+            Err(_) => return false,
+        };
+
+        // every ignore file that could apply, found once for our whole path
+        let mut ignore_files = vec![];
+        for excludes in self.excludes.clone() {
+            if let Some(rules) = self.load(excludes, None) {
+                ignore_files.push(rules);
+            }
+        }
+
+        // git doesn't look inside ignored directories, so nothing in them can be re-included
+        let mut current = self.root.clone();
+        let mut components = relative.components().peekable();
+        while let Some(component) = components.next() {
+            // the ignore files in a directory apply to everything under it
+            for name in &[".gitignore", MDNOTES_IGNORE] {
+                if let Some(rules) = self.load(current.join(name), Some(&current)) {
+                    ignore_files.push(rules);
+                }
+            }
+
+            if let Component::Normal(name) = component {
+                if name == ".git" {
+                    return true;
+                }
+                current.push(name);
+            }
+
+            let directory = components.peek().is_some() || current.is_dir();
+            if is_match(&ignore_files, &current, directory) {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// The rules in an ignore file, with anchored patterns relative to `base` or our root
+    fn load(&mut self, ignore_file: PathBuf, base: Option<&Path>) -> Option<Arc<Gitignore>> {
+        if self.checked.contains(&ignore_file) {
+            return self.cache.get(&ignore_file)?.rules.clone();
+        }
+        self.checked.insert(ignore_file.clone());
+
+        let stamp = fs::metadata(&ignore_file)
+            .ok()
+            .and_then(|metadata| Some((metadata.len(), metadata.modified().ok()?)));
+
+        if let Some(cached) = self.cache.get(&ignore_file) {
+            if cached.stamp == stamp {
+                return cached.rules.clone();
+            }
+        }
+
+        let rules = stamp
+            .and_then(|_| parse_ignore_file(&ignore_file, base.unwrap_or(&self.root)))
+            .map(Arc::new);
+        self.cache.insert(
+            ignore_file,
+            CachedRules {
+                stamp,
+                rules: rules.clone(),
+            },
+        );
+
+        rules
+    }
+}
+
+/// Whether the last rule that matches our path ignores it, with our ignore files from lowest to
+/// highest precedence
+fn is_match(ignore_files: &[Arc<Gitignore>], path: &Path, directory: bool) -> bool {
+    ignore_files
+        .iter()
+        .rev()
+        .map(|rules| rules.matched(path, directory))
+        .find(|matched| !matched.is_none())
+        .is_some_and(|matched| matches!(matched, Match::Ignore(_)))
+}
+
+/// Parse the rules in an ignore file, `None` if it has none
+fn parse_ignore_file(ignore_file: &Path, base: &Path) -> Option<Gitignore> {
+    let mut builder = GitignoreBuilder::new(base);
+    if let Some(e) = builder.add(ignore_file) {
+        // the rules we could read are still added
+        warn!("Skipping some ignore rules in {:?}: {}", ignore_file, e);
+    }
+
+    match builder.build() {
+        Ok(rules) if rules.is_empty() => None,
+        Ok(rules) => {
+            trace!("Loaded {} ignore rules from {:?}", rules.len(), ignore_file);
+
+            Some(rules)
+        }
+        Err(e) => {
+            warn!("Couldn't read {:?}: {}", ignore_file, e);
+
+            None
+        }
+    }
+}
+
+/// The directory holding our repository's shared files like `info/exclude`. A `.git` file points
+/// at the real git directory, which for worktrees points at the common directory in turn.
+fn git_common_dir(repo_root: &Path) -> Option<PathBuf> {
+    let dot_git = repo_root.join(".git");
+    let git_dir = if dot_git.is_dir() {
+        dot_git
+    } else {
+        let contents = fs::read_to_string(&dot_git).ok()?;
+        let git_dir = contents.trim().strip_prefix("gitdir:")?.trim();
+
+        repo_root.join(git_dir)
+    };
+
+    match fs::read_to_string(git_dir.join("commondir")) {
+        Ok(common_dir) => Some(git_dir.join(common_dir.trim())),
+        Err(_) => Some(git_dir),
+    }
+}
@@ -1,12 +1,41 @@
 #[macro_use]
 extern crate log;
 
+mod build_queue;
 mod c_interface;
+mod compression;
+mod config;
+mod error;
+mod events;
+#[cfg(any(test, feature = "test-util"))]
+mod fake_watcher;
+mod ignore;
+mod incremental;
+mod mdbook_html;
 mod mdnotes;
+mod output;
+mod overlay;
+mod poll_watcher;
+mod protocol;
 mod runtime;
 mod warp_fs;
+mod watch_set;
+mod watcher;
+mod worker;
 
 pub use c_interface::*;
+pub use config::{
+    BuildIsolation, BuildOutput, EnvironmentSetup, MdNotesRuntimeBuilder, MdNotesRuntimeConfig,
+    PortPolicy, WatchMode,
+};
+pub use error::MdNotesError;
+pub use events::{NotesEvent, NotesEventListener};
+#[cfg(any(test, feature = "test-util"))]
+pub use fake_watcher::FakeWatcher;
+pub use protocol::{Change, Diagnostic, NotesMessage, Protocol, PROTOCOL_VERSION};
 pub use runtime::*;
+pub use watcher::{NativeWatchers, NotesWatcher, WatcherFactory};
+pub use worker::{run_build_worker, BUILD_WORKER_COMMAND};
 
-type MdNotesError = String;
+/// Identifies a set of open notes for the lifetime of a runtime. Ids are never reused.
+pub type NotesId = u64;
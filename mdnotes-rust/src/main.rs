@@ -1,14 +1,234 @@
-use mdnotes::MdNotesRuntime;
+use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
+use mdbook::{Config, MDBook};
+use tokio::runtime::Runtime;
+
+use mdnotes::{
+    run_build_worker, BuildIsolation, MdNotesError, MdNotesRuntime, PortPolicy, WatchMode,
+    BUILD_WORKER_COMMAND,
+};
+
+const USAGE: &str = "\
+Usage:
+    mdnotes serve [--port <port>] [--bind <address>] [--build-timeout <seconds>]
+                  [--watch auto|native|polling] <dir>...
+        Serve each book with live reload until Ctrl-C, building in a separate process that's
+        stopped after the timeout if one is given
+    mdnotes build [--dest-dir <dir>] <dir>
+        Build the book once
+    mdnotes check <dir>
+        Report any problems with the book without writing anything to it
+";
+
+/// The exit code for bad arguments, failures get 1
+const USAGE_EXIT: i32 = 2;
 
 fn main() {
-    let runtime = MdNotesRuntime::new().unwrap();
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let code = match args.split_first() {
+        Some((command, args)) => match command.as_str() {
+            "serve" => serve(args),
+            "build" => build(args),
+            "check" => check(args),
+            // how we run our own isolated builds, so it's left out of our usage
+            BUILD_WORKER_COMMAND => process::exit(run_build_worker()),
+            "-h" | "--help" | "help" => {
+                print!("{}", USAGE);
+                Ok(())
+            }
+            other => Err(usage(&format!("Unknown command '{}'", other))),
+        },
+        None => Err(usage("Missing a command")),
+    };
+
+    if let Err(code) = code {
+        process::exit(code);
+    }
+}
+
+fn usage(message: &str) -> i32 {
+    eprintln!("{}\n\n{}", message, USAGE);
+
+    USAGE_EXIT
+}
+
+fn failure<E: std::fmt::Display>(e: E) -> i32 {
+    eprintln!("{}", e);
+
+    1
+}
+
+/// `--flag value` options and positional arguments
+type Args<'a> = (Vec<(&'a str, &'a str)>, Vec<&'a str>);
+
+fn parse_args<'a>(args: &'a [String], flags: &[&str]) -> Result<Args<'a>, i32> {
+    let mut options = vec![];
+    let mut positional = vec![];
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg.starts_with("--") {
+            if !flags.contains(&arg.as_str()) {
+                return Err(usage(&format!("Unknown option '{}'", arg)));
+            }
+
+            match args.next() {
+                Some(value) => options.push((arg.as_str(), value.as_str())),
+                None => return Err(usage(&format!("Missing a value for '{}'", arg))),
+            }
+        } else {
+            positional.push(arg.as_str());
+        }
+    }
+
+    Ok((options, positional))
+}
+
+fn serve(args: &[String]) -> Result<(), i32> {
+    let (options, book_dirs) =
+        parse_args(args, &["--port", "--bind", "--build-timeout", "--watch"])?;
+    if book_dirs.is_empty() {
+        return Err(usage("Missing a book directory to serve"));
+    }
+
+    // our own port, so that we don't take the app's
+    let mut builder = MdNotesRuntime::builder().port(PortPolicy::persisted_for("cli"));
+    for (flag, value) in options {
+        match flag {
+            "--port" => {
+                let port = value
+                    .parse()
+                    .map_err(|e| usage(&format!("Invalid port '{}': {}", value, e)))?;
+                builder = builder.port(PortPolicy::Preferred(port));
+            }
+            "--build-timeout" => {
+                let seconds: f64 = value
+                    .parse()
+                    .ok()
+                    .filter(|seconds: &f64| seconds.is_finite() && *seconds > 0.0)
+                    .ok_or_else(|| usage(&format!("Invalid build timeout '{}'", value)))?;
+                builder = builder.build_isolation(BuildIsolation::Process {
+                    worker: env::current_exe().map_err(failure)?,
+                    timeout: Duration::from_secs_f64(seconds),
+                });
+            }
+            "--watch" => {
+                let mode = WatchMode::from_name(value)
+                    .ok_or_else(|| usage(&format!("Invalid watch mode '{}'", value)))?;
+                builder = builder.watch_mode(mode);
+            }
+            _ => {
+                let address: IpAddr = value
+                    .parse()
+                    .map_err(|e| usage(&format!("Invalid address '{}': {}", value, e)))?;
+                builder = builder.bind_address(address);
+            }
+        }
+    }
+
+    let runtime = builder.build().map_err(failure)?;
 
-    let home_dir = dirs::home_dir().unwrap();
-    let id = runtime.open_notes(home_dir.join("code/notes"));
+    let mut notes_ids = vec![];
+    for book_dir in book_dirs {
+        let notes_id = runtime.open_notes(book_dir.into()).map_err(failure)?;
+        notes_ids.push(notes_id);
 
-    println!("http://localhost:{}/{}/static/", runtime.server_port(), id);
+        println!(
+            "Serving {} at http://{}/{}/static/",
+            book_dir,
+            runtime.server_address(),
+            runtime.notes_slug(notes_id).unwrap_or_default()
+        );
+    }
 
-    // loop {}
+    Runtime::new()
+        .map_err(failure)?
+        .block_on(tokio::signal::ctrl_c())
+        .map_err(failure)?;
+
+    for notes_id in notes_ids {
+        runtime.close_notes(notes_id).map_err(failure)?;
+    }
+
+    Ok(())
+}
+
+fn build(args: &[String]) -> Result<(), i32> {
+    let (options, book_dirs) = parse_args(args, &["--dest-dir"])?;
+    let book_dir = single_book_dir(&book_dirs)?;
+
+    let mut book = MDBook::load(book_dir)
+        .map_err(MdNotesError::Config)
+        .map_err(failure)?;
+
+    if let Some((_, dest_dir)) = options.first() {
+        // mdbook resolves relative build directories from the book, but we want them from here
+        let current_dir = env::current_dir().map_err(failure)?;
+        book.config.build.build_dir = current_dir.join(PathBuf::from(dest_dir));
+    }
+
+    book.build().map_err(MdNotesError::Build).map_err(failure)?;
+
+    println!(
+        "Built {} into {}",
+        book_dir,
+        book.root.join(&book.config.build.build_dir).display()
+    );
+
+    Ok(())
+}
+
+fn check(args: &[String]) -> Result<(), i32> {
+    let (_, book_dirs) = parse_args(args, &[])?;
+    let book_dir = single_book_dir(&book_dirs)?;
+
+    // build somewhere else so that checking never touches the book
+    let check_dir = env::temp_dir().join(format!("mdnotes-check-{}", process::id()));
+    let result = check_book(Path::new(book_dir), &check_dir);
+    let _ = fs::remove_dir_all(&check_dir);
+
+    match result {
+        Ok(()) => {
+            println!("{} has no problems", book_dir);
+
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("{}: {}", book_dir, e);
+
+            Err(1)
+        }
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn check_book(book_dir: &Path, check_dir: &Path) -> Result<(), MdNotesError> {
+    let config_path = book_dir.join("book.toml");
+    let mut config = if config_path.exists() {
+        Config::from_disk(&config_path).map_err(MdNotesError::Config)?
+    } else {
+        Config::default()
+    };
+
+    // missing chapters are problems, not something to fill in
+    config.build.create_missing = false;
+    config.build.build_dir = check_dir.to_path_buf();
+
+    let book = MDBook::load_with_config(book_dir, config).map_err(MdNotesError::Config)?;
+
+    book.build().map_err(MdNotesError::Build)
+}
 
-    runtime.close_notes(id);
+fn single_book_dir<'a>(book_dirs: &[&'a str]) -> Result<&'a str, i32> {
+    match book_dirs {
+        [book_dir] => Ok(book_dir),
+        [] => Err(usage("Missing a book directory")),
+        _ => Err(usage("Expected a single book directory")),
+    }
 }
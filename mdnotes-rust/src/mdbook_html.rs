@@ -0,0 +1,420 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use elasticlunr::Index;
+use mdbook::book::{Book, BookItem};
+use mdbook::config::{Playpen, Search};
+use mdbook::errors::*;
+use mdbook::utils;
+use pulldown_cmark::*;
+use regex::{Captures, Regex};
+use serde::Serialize;
+
+// The parts of mdbook's html renderer we need to re-render single chapters, which it keeps private.
+// Taken from: https://github.com/rust-lang/mdBook/blob/v0.3.7/src/renderer/html_handlebars/
+// Our mdbook dependency is pinned to this version, update both together.
+
+pub fn post_process(rendered: String, playpen_config: &Playpen) -> String {
+    let rendered = build_header_links(&rendered);
+    let rendered = fix_code_blocks(&rendered);
+
+    add_playpen_pre(&rendered, playpen_config)
+}
+
+fn build_header_links(html: &str) -> String {
+    let regex = Regex::new(r"<h(\d)>(.*?)</h\d>").unwrap();
+    let mut id_counter = HashMap::new();
+
+    regex
+        .replace_all(html, |caps: &Captures<'_>| {
+            let level = caps[1]
+                .parse()
+                .expect("Regex should ensure we only ever get numbers here");
+
+            insert_link_into_header(level, &caps[2], &mut id_counter)
+        })
+        .into_owned()
+}
+
+/// Insert a sinle link into a header, making sure each link gets its own
+/// unique ID by appending an auto-incremented number (if necessary).
+fn insert_link_into_header(
+    level: usize,
+    content: &str,
+    id_counter: &mut HashMap<String, usize>,
+) -> String {
+    let raw_id = utils::id_from_content(content);
+
+    let id_count = id_counter.entry(raw_id.clone()).or_insert(0);
+
+    let id = match *id_count {
+        0 => raw_id,
+        other => format!("{}-{}", raw_id, other),
+    };
+
+    *id_count += 1;
+
+    format!(
+        r##"<h{level}><a class="header" href="#{id}" id="{id}">{text}</a></h{level}>"##,
+        level = level,
+        id = id,
+        text = content
+    )
+}
+
+// The rust book uses annotations for rustdoc to test code snippets,
+// like the following:
+// ```rust,should_panic
+// fn main() {
+//     // Code here
+// }
+// ```
+// This function replaces all commas by spaces in the code block classes
+fn fix_code_blocks(html: &str) -> String {
+    let regex = Regex::new(r##"<code([^>]+)class="([^"]+)"([^>]*)>"##).unwrap();
+    regex
+        .replace_all(html, |caps: &Captures<'_>| {
+            let before = &caps[1];
+            let classes = &caps[2].replace(",", " ");
+            let after = &caps[3];
+
+            format!(
+                r#"<code{before}class="{classes}"{after}>"#,
+                before = before,
+                classes = classes,
+                after = after
+            )
+        })
+        .into_owned()
+}
+
+fn add_playpen_pre(html: &str, playpen_config: &Playpen) -> String {
+    let regex = Regex::new(r##"((?s)<code[^>]?class="([^"]+)".*?>(.*?)</code>)"##).unwrap();
+    regex
+        .replace_all(html, |caps: &Captures<'_>| {
+            let text = &caps[1];
+            let classes = &caps[2];
+            let code = &caps[3];
+
+            if classes.contains("language-rust") {
+                if (!classes.contains("ignore") && !classes.contains("noplaypen"))
+                    || classes.contains("mdbook-runnable")
+                {
+                    // wrap the contents in an external pre block
+                    format!(
+                        "<pre class=\"playpen\"><code class=\"{}\">{}</code></pre>",
+                        classes,
+                        {
+                            let content: Cow<'_, str> = if playpen_config.editable
+                                && classes.contains("editable")
+                                || text.contains("fn main")
+                                || text.contains("quick_main!")
+                            {
+                                code.into()
+                            } else {
+                                // we need to inject our own main
+                                let (attrs, code) = partition_source(code);
+
+                                format!(
+                                    "\n# #![allow(unused_variables)]\n{}#fn main() {{\n{}#}}",
+                                    attrs, code
+                                )
+                                .into()
+                            };
+                            hide_lines(&content)
+                        }
+                    )
+                } else {
+                    format!("<code class=\"{}\">{}</code>", classes, hide_lines(code))
+                }
+            } else {
+                // not language-rust, so no-op
+                text.to_owned()
+            }
+        })
+        .into_owned()
+}
+
+fn hide_lines(content: &str) -> String {
+    static BORING_LINES_REGEX: OnceLock<Regex> = OnceLock::new();
+    let boring_lines_regex =
+        BORING_LINES_REGEX.get_or_init(|| Regex::new(r"^(\s*)#(.?)(.*)$").unwrap());
+
+    let mut result = String::with_capacity(content.len());
+    for line in content.lines() {
+        if let Some(caps) = boring_lines_regex.captures(line) {
+            if &caps[2] == "#" {
+                result += &caps[1];
+                result += &caps[2];
+                result += &caps[3];
+                result += "\n";
+                continue;
+            } else if &caps[2] != "!" && &caps[2] != "[" {
+                result += "<span class=\"boring\">";
+                result += &caps[1];
+                if &caps[2] != " " {
+                    result += &caps[2];
+                }
+                result += &caps[3];
+                result += "\n";
+                result += "</span>";
+                continue;
+            }
+        }
+        result += line;
+        result += "\n";
+    }
+    result
+}
+
+fn partition_source(s: &str) -> (String, String) {
+    let mut after_header = false;
+    let mut before = String::new();
+    let mut after = String::new();
+
+    for line in s.lines() {
+        let trimline = line.trim();
+        let header = trimline.chars().all(char::is_whitespace) || trimline.starts_with("#![");
+        if !header || after_header {
+            after_header = true;
+            after.push_str(line);
+            after.push('\n');
+        } else {
+            before.push_str(line);
+            before.push('\n');
+        }
+    }
+
+    (before, after)
+}
+
+/// Create the contents of `searchindex.json`
+pub fn search_index(search_config: &Search, book: &Book) -> Result<String> {
+    let mut index = Index::new(&["title", "body", "breadcrumbs"]);
+    let mut doc_urls = Vec::with_capacity(book.sections.len());
+
+    for item in book.iter() {
+        render_item(&mut index, search_config, &mut doc_urls, item)?;
+    }
+
+    let index = write_to_json(index, search_config, doc_urls)?;
+    if index.len() > 10_000_000 {
+        warn!("searchindex.json is very large ({} bytes)", index.len());
+    }
+
+    Ok(index)
+}
+
+/// Uses the given arguments to construct a search document, then inserts it to the given index.
+fn add_doc(
+    index: &mut Index,
+    doc_urls: &mut Vec<String>,
+    anchor_base: &str,
+    section_id: &Option<String>,
+    items: &[&str],
+) {
+    let url = if let Some(ref id) = *section_id {
+        Cow::Owned(format!("{}#{}", anchor_base, id))
+    } else {
+        Cow::Borrowed(anchor_base)
+    };
+    let url = utils::collapse_whitespace(url.trim());
+    let doc_ref = doc_urls.len().to_string();
+    doc_urls.push(url.into());
+
+    let items = items.iter().map(|&x| utils::collapse_whitespace(x.trim()));
+    index.add_doc(&doc_ref, items);
+}
+
+/// Renders markdown into flat unformatted text and adds it to the search index.
+fn render_item(
+    index: &mut Index,
+    search_config: &Search,
+    doc_urls: &mut Vec<String>,
+    item: &BookItem,
+) -> Result<()> {
+    let chapter = match *item {
+        BookItem::Chapter(ref ch) => ch,
+        _ => return Ok(()),
+    };
+
+    let filepath = Path::new(&chapter.path).with_extension("html");
+    let filepath = filepath
+        .to_str()
+        .chain_err(|| "Could not convert HTML path to str")?;
+    let anchor_base = utils::fs::normalize_path(filepath);
+
+    let mut p = utils::new_cmark_parser(&chapter.content).peekable();
+
+    let mut in_heading = false;
+    let max_section_depth = u32::from(search_config.heading_split_level);
+    let mut section_id = None;
+    let mut heading = String::new();
+    let mut body = String::new();
+    let mut breadcrumbs = chapter.parent_names.clone();
+    let mut footnote_numbers = HashMap::new();
+
+    while let Some(event) = p.next() {
+        match event {
+            Event::Start(Tag::Heading(i)) if i <= max_section_depth => {
+                if !heading.is_empty() {
+                    // Section finished, the next heading is following now
+                    // Write the data to the index, and clear it for the next section
+                    add_doc(
+                        index,
+                        doc_urls,
+                        &anchor_base,
+                        &section_id,
+                        &[&heading, &body, &breadcrumbs.join(" » ")],
+                    );
+                    section_id = None;
+                    heading.clear();
+                    body.clear();
+                    breadcrumbs.pop();
+                }
+
+                in_heading = true;
+            }
+            Event::End(Tag::Heading(i)) if i <= max_section_depth => {
+                in_heading = false;
+                section_id = Some(utils::id_from_content(&heading));
+                breadcrumbs.push(heading.clone());
+            }
+            Event::Start(Tag::FootnoteDefinition(name)) => {
+                let number = footnote_numbers.len() + 1;
+                footnote_numbers.entry(name).or_insert(number);
+            }
+            Event::Html(html) => {
+                let mut html_block = html.into_string();
+
+                // As of pulldown_cmark 0.6, html events are no longer contained
+                // in an HtmlBlock tag. We must collect consecutive Html events
+                // into a block ourselves.
+                while let Some(Event::Html(html)) = p.peek() {
+                    html_block.push_str(html);
+                    p.next();
+                }
+
+                body.push_str(&clean_html(&html_block));
+            }
+            Event::Start(_) | Event::End(_) | Event::Rule | Event::SoftBreak | Event::HardBreak => {
+                // Insert spaces where HTML output would usually seperate text
+                // to ensure words don't get merged together
+                if in_heading {
+                    heading.push(' ');
+                } else {
+                    body.push(' ');
+                }
+            }
+            Event::Text(text) | Event::Code(text) => {
+                if in_heading {
+                    heading.push_str(&text);
+                } else {
+                    body.push_str(&text);
+                }
+            }
+            Event::FootnoteReference(name) => {
+                let len = footnote_numbers.len() + 1;
+                let number = footnote_numbers.entry(name).or_insert(len);
+                body.push_str(&format!(" [{}] ", number));
+            }
+            Event::TaskListMarker(_checked) => {}
+        }
+    }
+
+    if !heading.is_empty() {
+        // Make sure the last section is added to the index
+        add_doc(
+            index,
+            doc_urls,
+            &anchor_base,
+            &section_id,
+            &[&heading, &body, &breadcrumbs.join(" » ")],
+        );
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::field_reassign_with_default)]
+fn write_to_json(index: Index, search_config: &Search, doc_urls: Vec<String>) -> Result<String> {
+    use elasticlunr::config::{SearchBool, SearchOptions, SearchOptionsField};
+
+    #[derive(Serialize)]
+    struct ResultsOptions {
+        limit_results: u32,
+        teaser_word_count: u32,
+    }
+
+    #[derive(Serialize)]
+    struct SearchindexJson {
+        /// The options used for displaying search results
+        results_options: ResultsOptions,
+        /// The searchoptions for elasticlunr.js
+        search_options: SearchOptions,
+        /// Used to lookup a document's URL from an integer document ref.
+        doc_urls: Vec<String>,
+        /// The index for elasticlunr.js
+        index: elasticlunr::Index,
+    }
+
+    let mut fields = BTreeMap::new();
+    let mut opt = SearchOptionsField::default();
+    opt.boost = Some(search_config.boost_title);
+    fields.insert("title".into(), opt);
+    opt.boost = Some(search_config.boost_paragraph);
+    fields.insert("body".into(), opt);
+    opt.boost = Some(search_config.boost_hierarchy);
+    fields.insert("breadcrumbs".into(), opt);
+
+    let search_options = SearchOptions {
+        bool: if search_config.use_boolean_and {
+            SearchBool::And
+        } else {
+            SearchBool::Or
+        },
+        expand: search_config.expand,
+        fields,
+    };
+
+    let results_options = ResultsOptions {
+        limit_results: search_config.limit_results,
+        teaser_word_count: search_config.teaser_word_count,
+    };
+
+    let json_contents = SearchindexJson {
+        results_options,
+        search_options,
+        doc_urls,
+        index,
+    };
+
+    // By converting to serde_json::Value as an intermediary, we use a
+    // BTreeMap internally and can force a stable ordering of map keys.
+    let json_contents = serde_json::to_value(&json_contents)?;
+    let json_contents = serde_json::to_string(&json_contents)?;
+
+    Ok(json_contents)
+}
+
+fn clean_html(html: &str) -> String {
+    static AMMONIA: OnceLock<ammonia::Builder<'static>> = OnceLock::new();
+    let ammonia = AMMONIA.get_or_init(|| {
+        let mut clean_content = HashSet::new();
+        clean_content.insert("script");
+        clean_content.insert("style");
+        let mut builder = ammonia::Builder::new();
+        builder
+            .tags(HashSet::new())
+            .tag_attributes(HashMap::new())
+            .generic_attributes(HashSet::new())
+            .link_rel(None)
+            .allowed_classes(HashMap::new())
+            .clean_content_tags(clean_content);
+        builder
+    });
+
+    ammonia.clean(html).to_string()
+}
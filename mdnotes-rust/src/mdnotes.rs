@@ -1,127 +1,339 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::RecvTimeoutError;
-use std::sync::{mpsc, Arc};
-use std::time::Duration;
-use std::{fs, thread};
+use std::sync::{mpsc, Arc, RwLock};
+use std::time::{Duration, Instant};
+use std::{mem, thread};
 
-use mdbook::errors::Error as MDBookError;
-use mdbook::MDBook;
-use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use mdbook::{Config, MDBook};
+use notify::DebouncedEvent;
 use tokio::sync::broadcast;
 use tokio::sync::broadcast::{Receiver, Sender};
 
-use crate::MdNotesError;
+use crate::build_queue::{BuildLimiter, BuildQueue, Changes};
+use crate::events::{NotesEvent, NotesEvents};
+use crate::ignore::IgnoreMatcher;
+use crate::output::NotesOutput;
+use crate::protocol::{Change, Diagnostic, NotesMessage};
+use crate::watch_set::{WatchSet, Watches};
+use crate::watcher::WatcherFactory;
+use crate::worker::{BuildRequest, Built};
+use crate::{BuildIsolation, BuildOutput, MdNotesError, NotesId, WatchMode};
+
+/// Everything our notes need from their runtime
+#[derive(Clone)]
+pub struct NotesContext {
+    /// The `host:port` our notes are served from
+    pub server_host: String,
+    pub broadcast_buffer: usize,
+    pub events: NotesEvents,
+    pub build_output: BuildOutput,
+    pub build_limiter: BuildLimiter,
+    pub build_isolation: BuildIsolation,
+    pub watcher: Arc<dyn WatcherFactory>,
+    pub watch_mode: WatchMode,
+    pub poll_interval: Duration,
+}
 
 pub struct MdNotes {
-    pub html_dir: PathBuf,
+    id: NotesId,
+    pub slug: String,
+    pub output: NotesOutput,
     shutdown_hook: Arc<AtomicBool>,
-    broadcast: Sender<String>,
+    build_queue: Arc<BuildQueue>,
+    broadcast: Sender<NotesMessage>,
+    events: NotesEvents,
+    /// Why our last rebuild failed, cleared by the next successful build
+    build_failure: BuildFailure,
+    /// Our book's directory was removed, so we've stopped watching and building it
+    orphaned: Arc<AtomicBool>,
 }
 
+type BuildFailure = Arc<RwLock<Option<Diagnostic>>>;
+
 impl MdNotes {
-    pub fn new(id: u8, book_dir: PathBuf, port: u16) -> Result<MdNotes, String> {
-        let livereload_url = format!("ws://localhost:{}/{}/ws", port, id);
-        let book = build_book(&book_dir, &livereload_url)
-            .map_err(|e| format!("Couldn't rebuild the build: {}", e))?;
-        let html_dir = book.build_dir_for("html");
+    pub fn new(
+        id: NotesId,
+        slug: String,
+        book_dir: PathBuf,
+        context: NotesContext,
+    ) -> Result<MdNotes, MdNotesError> {
+        let NotesContext {
+            server_host,
+            broadcast_buffer,
+            events,
+            build_output,
+            build_limiter,
+            build_isolation,
+            watcher,
+            watch_mode,
+            poll_interval,
+        } = context;
+        let build_queue = Arc::new(BuildQueue::default());
+        let builder = NotesBuilder {
+            id,
+            book_dir: book_dir.clone(),
+            livereload_url: format!("ws://{}/{}/ws", server_host, slug),
+            build_dir: build_output.build_dir(id, &book_dir),
+            isolation: build_isolation,
+            events: events.clone(),
+            queue: build_queue.clone(),
+        };
+        let book = {
+            let _permit = build_limiter.acquire(|| false);
+            builder.build()?
+        };
+        let output = NotesOutput::new(&book, &build_output)?;
 
-        // we don't care about this initial receiver
-        let (sender, _) = broadcast::channel::<String>(10);
+        // we don't care about this initial receiver, and our channel needs room for at least 1
+        let (sender, _) = broadcast::channel::<NotesMessage>(broadcast_buffer.max(1));
+        let build_failure = BuildFailure::default();
 
-        let shutdown_hook = start_fs_watcher(&book, livereload_url, sender.clone())?;
+        let orphaned = Arc::new(AtomicBool::new(false));
+
+        let watch_set = WatchSet::for_book(&book);
+        let (watch_sets, watch_set_updates) = mpsc::channel();
+        let shutdown_hook = start_fs_watcher(
+            &book,
+            (watch_set.clone(), watch_set_updates),
+            build_queue.clone(),
+            watcher.as_ref(),
+            watch_mode,
+            poll_interval,
+            WatchReporter {
+                id,
+                broadcast: sender.clone(),
+                events: events.clone(),
+                orphaned: orphaned.clone(),
+            },
+        )?;
+
+        BuildWorker {
+            slug: slug.clone(),
+            book_dir,
+            layout: BookLayout::of(&book),
+            full_reload: false,
+            builder,
+            output: output.clone(),
+            broadcast: sender.clone(),
+            build_failure: build_failure.clone(),
+            queue: build_queue.clone(),
+            limiter: build_limiter,
+            watch_set,
+            watch_sets,
+        }
+        .start();
 
         Ok(MdNotes {
-            html_dir,
+            id,
+            slug,
+            output,
             shutdown_hook,
+            build_queue,
             broadcast: sender,
+            events,
+            build_failure,
+            orphaned,
         })
     }
 
-    pub fn get_ws_receiver(&self) -> Receiver<String> {
+    pub fn get_ws_receiver(&self) -> Receiver<NotesMessage> {
         self.broadcast.subscribe()
     }
+
+    /// Why our last rebuild failed, if it did. Our output is left over from the last good build.
+    pub fn build_failure(&self) -> Option<Diagnostic> {
+        self.build_failure
+            .read()
+            .expect("Our build failure lock should never be poisoned")
+            .clone()
+    }
+
+    /// Whether our book's directory was removed, in which case we've stopped building it
+    pub fn is_orphaned(&self) -> bool {
+        self.orphaned.load(Ordering::Relaxed)
+    }
 }
 
 impl Drop for MdNotes {
     fn drop(&mut self) {
-        // signal our fs watcher to shutdown
+        // signal our fs watcher and build worker to shutdown
         self.shutdown_hook.store(true, Ordering::Relaxed);
+        self.build_queue.shutdown();
+        // our output on disk outlives us, but nobody will ask for it compressed again
+        self.output.forget_compressed();
+
+        // according to the doc, an error means there were no receivers, so ignore it
+        let _ = self.broadcast.send(NotesMessage::NotebookClosed);
+        self.events.close(self.id);
+    }
+}
+
+/// A url friendly name for our book, taken from its title or else its directory name
+pub fn book_slug(book_dir: &Path) -> String {
+    let config_path = book_dir.join("book.toml");
+    // an invalid config will be reported when we build, so just fall back on our directory name
+    let title = Config::from_disk(&config_path)
+        .ok()
+        .and_then(|config| config.book.title);
+
+    let slug = title
+        .map(|title| slugify(&title))
+        .filter(|slug| !slug.is_empty())
+        .or_else(|| {
+            book_dir
+                .file_name()
+                .map(|name| slugify(&name.to_string_lossy()))
+        })
+        .filter(|slug| !slug.is_empty())
+        .unwrap_or_else(|| "notes".to_string());
+
+    // purely numeric mounts are reserved for our notes ids
+    if slug.chars().all(|c| c.is_ascii_digit()) {
+        format!("notes-{}", slug)
+    } else {
+        slug
     }
 }
 
+fn slugify(name: &str) -> String {
+    let mut slug = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+
+    slug.trim_end_matches('-').to_string()
+}
+
+/// Watch everything in our first watch set, following along as our builds send us new ones
 fn start_fs_watcher(
     book: &MDBook,
-    livereload_url: String,
-    broadcast: Sender<String>,
+    (watch_set, watch_set_updates): (WatchSet, mpsc::Receiver<WatchSet>),
+    build_queue: Arc<BuildQueue>,
+    watcher: &dyn WatcherFactory,
+    watch_mode: WatchMode,
+    poll_interval: Duration,
+    reporter: WatchReporter,
 ) -> Result<Arc<AtomicBool>, MdNotesError> {
     let book_dir = book.root.clone();
-    let source_dir = book.source_dir();
-    let theme_dir = book.theme_dir();
+    let mut ignores = IgnoreMatcher::new(
+        &book_dir,
+        Some(book.root.join(&book.config.build.build_dir)),
+    );
 
     let (sender, receiver) = mpsc::channel();
 
-    let mut watcher = RecommendedWatcher::new(sender, Duration::from_millis(100))
-        .map_err(|e| format!("Error watching file system: {}", e))?;
-
-    let mut watching_something = false;
-    for watch_path in &[&source_dir, &theme_dir, &book.root.join("book.toml")] {
-        match watcher.watch(watch_path, RecursiveMode::Recursive) {
-            Ok(_) => watching_something = true,
-            Err(e) => warn!("Couldn't watch book directory '{:?}': {}", watch_path, e),
-        }
-    }
+    let mode = WatchSet::watch_mode(book).unwrap_or(watch_mode);
+    let mut watches = Watches::new(watcher, mode, poll_interval, sender)?;
 
-    if watching_something {
+    if watches.update(watch_set) {
         let shutdown = Arc::new(AtomicBool::new(false));
         let fs_shutdown = shutdown.clone();
         thread::spawn(move || {
-            // take ownership of watcher in this thread so that we can drop it when we're done
-            let _ = watcher;
-            let reload_event = "reload".to_string();
+            // take ownership of our watches in this thread so that we can drop them when we're done
+            let mut watches = watches;
+            // an event our watcher took while checking whether any were waiting
+            let mut waiting = None;
 
             // check if we should shutdown every loop
             while !fs_shutdown.load(Ordering::Relaxed) {
+                watches.between_batches(&mut || match receiver.try_recv() {
+                    Ok(event) => {
+                        waiting = Some(event);
+                        false
+                    }
+                    Err(_) => true,
+                });
+
                 // only wait for 1 second, we want to make sure to check our shutdown status
-                match receiver.recv_timeout(Duration::from_secs(1)) {
+                let next_event = match waiting.take() {
+                    Some(event) => Ok(event),
+                    None => receiver.recv_timeout(poll_interval.min(Duration::from_secs(1))),
+                };
+                let mut events: Vec<_> = match next_event {
                     Ok(first_event) => {
-                        thread::sleep(Duration::from_millis(50));
-                        let other_events = receiver.try_iter();
+                        watches.native_event();
 
-                        let all_events = std::iter::once(first_event).chain(other_events);
+                        thread::sleep(Duration::from_millis(50));
+                        std::iter::once(first_event)
+                            .chain(receiver.try_iter())
+                            .collect()
+                    }
+                    Err(RecvTimeoutError::Timeout) => vec![],
+                    Err(RecvTimeoutError::Disconnected) => {
+                        // on disconnect, we can stop checking for fs events
+                        break;
+                    }
+                };
+                // checked on every pass, since a removed book may never send us another event
+                if !book_dir.is_dir() {
+                    reporter.orphaned(&book_dir);
+                    build_queue.shutdown();
+                    break;
+                }
+                // our last build may have added or removed an include, or pointed us elsewhere
+                for watch_set in watch_set_updates.try_iter() {
+                    watches.update(watch_set);
+                }
+                // anything our native watcher silently missed
+                events.extend(
+                    watches
+                        .check_canary()
+                        .into_iter()
+                        .map(DebouncedEvent::Write),
+                );
+                if events.is_empty() {
+                    continue;
+                }
 
-                        let paths = all_events.filter_map(|event| {
-                            trace!("Received filesystem event: {:?}", event);
+                let mut rescan = false;
+                let paths: Vec<_> = events
+                    .into_iter()
+                    .flat_map(|event| {
+                        trace!("Received filesystem event: {:?}", event);
 
-                            match event {
-                                DebouncedEvent::Create(path)
-                                | DebouncedEvent::Write(path)
-                                | DebouncedEvent::Remove(path)
-                                | DebouncedEvent::Rename(_, path) => Some(path),
-                                _ => None,
+                        match event {
+                            DebouncedEvent::Create(path)
+                            | DebouncedEvent::Write(path)
+                            | DebouncedEvent::Remove(path) => vec![path],
+                            // a file moved out of our book is as gone as a removed one
+                            DebouncedEvent::Rename(from, to) => vec![from, to],
+                            // our watcher lost track of what changed
+                            DebouncedEvent::Rescan => {
+                                rescan = true;
+                                vec![]
+                            }
+                            DebouncedEvent::Error(e, path) => {
+                                reporter.watch_failed(match path {
+                                    Some(path) => format!("Couldn't watch {:?}: {}", path, e),
+                                    None => format!("Couldn't watch the book: {}", e),
+                                });
+                                vec![]
                             }
-                        });
+                            _ => vec![],
+                        }
+                    })
+                    // we watch whole directories for single files
+                    .filter(|path| watches.set().contains(path))
+                    .collect();
 
-                        if found_unignored_files(paths, &book_dir) {
-                            debug!("Reloading book: {:?}", book_dir);
+                let changed_paths = ignores.unignored(paths);
+                if rescan {
+                    info!("Rebuilding all of {:?} after a rescan", book_dir);
 
-                            match build_book(&book_dir, &livereload_url) {
-                                Ok(_) => (),
-                                Err(e) => warn!("Couldn't rebuild the book: {}", e),
-                            }
+                    build_queue.push_everything();
+                }
+                if !changed_paths.is_empty() {
+                    debug!("Queueing a build of: {:?}", book_dir);
 
-                            // according to the doc, an error means there were no receivers, so ignore it
-                            let _ = broadcast.send(reload_event.clone());
-                        }
-                    }
-                    Err(RecvTimeoutError::Timeout) => (), // ignore timeouts
-                    Err(RecvTimeoutError::Disconnected) => {
-                        // on disconnect, we can stop checking for fs events
-                        break;
-                    }
+                    build_queue.push(changed_paths);
                 }
             }
-            info!("Stopped watching the fs for {:?}", source_dir);
+            info!("Stopped watching the fs for {:?}", book_dir);
         });
 
         Ok(shutdown)
@@ -132,45 +344,359 @@ fn start_fs_watcher(
     }
 }
 
-fn found_unignored_files<I>(mut paths: I, book_dir: &PathBuf) -> bool
-where
-    I: Iterator<Item = PathBuf>,
-{
-    if let Some(gitignore_path) = book_dir
-        .ancestors()
-        .map(|p| p.join(".gitignore"))
-        .find(|p| p.exists())
-        .map(|p| fs::canonicalize(p).expect("This should exist and therefore not error"))
-    {
-        match gitignore::File::new(&gitignore_path) {
-            Ok(exclusion_checker) => paths.any(|path| match exclusion_checker.is_excluded(&path) {
-                Ok(excluded) => !excluded,
-                Err(e) => {
-                    warn!(
-                        "Found an error when checking .gitignore exclusion for {:?}: {}",
-                        path, e
-                    );
-                    true
+/// How our fs watcher lets our clients and listener know that something went wrong
+struct WatchReporter {
+    id: NotesId,
+    broadcast: Sender<NotesMessage>,
+    events: NotesEvents,
+    orphaned: Arc<AtomicBool>,
+}
+
+impl WatchReporter {
+    fn watch_failed(&self, message: String) {
+        warn!("{}", message);
+
+        self.events
+            .emit(self.id, NotesEvent::WatchFailed(message.clone()));
+        let _ = self.broadcast.send(NotesMessage::WatchFailed { message });
+    }
+
+    fn orphaned(&self, book_dir: &Path) {
+        warn!("{:?} was removed, so it won't be built again", book_dir);
+
+        self.orphaned.store(true, Ordering::Relaxed);
+        self.events.emit(self.id, NotesEvent::Orphaned);
+        let _ = self.broadcast.send(NotesMessage::NotebookOrphaned);
+    }
+}
+
+/// Builds our notes in the background as changes come in from our fs watcher
+struct BuildWorker {
+    slug: String,
+    book_dir: PathBuf,
+    layout: BookLayout,
+    /// Our layout changed, so our next successful build should reload everything
+    full_reload: bool,
+    builder: NotesBuilder,
+    output: NotesOutput,
+    broadcast: Sender<NotesMessage>,
+    build_failure: BuildFailure,
+    queue: Arc<BuildQueue>,
+    limiter: BuildLimiter,
+    /// What our fs watcher is watching, as of our last good build
+    watch_set: WatchSet,
+    watch_sets: mpsc::Sender<WatchSet>,
+}
+
+impl BuildWorker {
+    fn start(mut self) {
+        thread::spawn(move || {
+            // we can only update our output in place if our last build worked
+            let mut last_build_worked = true;
+
+            while let Some(mut changes) = self.queue.wait() {
+                let _permit = match self.limiter.acquire(|| self.queue.is_shutdown()) {
+                    Some(permit) => permit,
+                    None => break,
+                };
+
+                // anything that changed while we waited for our turn is part of this build
+                match self.queue.drain() {
+                    Some(more_changes) => changes.merge(more_changes),
+                    None => break,
+                }
+
+                last_build_worked = self.build(&changes, last_build_worked);
+            }
+
+            info!("Stopped building {:?}", self.book_dir);
+        });
+    }
+
+    /// Rebuild our notes and tell our clients how it went
+    fn build(&mut self, changed: &Changes, last_build_worked: bool) -> bool {
+        debug!("Reloading book: {:?}", self.book_dir);
+
+        let changed_paths = &changed.paths;
+        if changed.everything {
+            self.full_reload = true;
+        }
+        if changed_paths.contains(&self.book_dir.join("book.toml")) {
+            // a broken config is reported by our build
+            if let Some(layout) = BookLayout::load(&self.book_dir) {
+                if layout != self.layout {
+                    info!("{:?} now builds from {:?}", self.book_dir, layout);
+
+                    self.layout = layout;
+                    self.full_reload = true;
                 }
-            }),
+            }
+        }
+
+        // according to the doc, an error means there were no receivers, so ignore it
+        let _ = self.broadcast.send(NotesMessage::BuildStarted);
+
+        let changes: Vec<_> = changed_paths
+            .iter()
+            .map(|path| change_for(path, &self.book_dir, &self.layout.source_dir, &self.slug))
+            .collect();
+
+        let result = if last_build_worked && !changed.everything {
+            self.builder.rebuild(changed_paths, &self.output)
+        } else {
+            self.builder.build_into(&self.output)
+        };
+        let result = result.map(|built| self.update_watch_set(&built, changed_paths));
+        let worked = result.is_ok();
+
+        // our notes were closed while we built, so there's no one left to tell
+        if self.queue.is_shutdown() {
+            return worked;
+        }
+
+        let failure = match result {
+            Ok(()) => None,
             Err(e) => {
-                warn!("Couldn't parse our .gitignore: {}", e);
+                warn!("Couldn't rebuild the book: {}", e);
 
-                paths.count() > 0
+                let file = failing_file(&e.causes(), &self.book_dir, &self.layout.source_dir);
+                Some(Diagnostic::from_error(&e, file))
             }
+        };
+
+        // update our failure before telling anyone, so reloads see it
+        *self
+            .build_failure
+            .write()
+            .expect("Our build failure lock should never be poisoned") = failure.clone();
+
+        let message = match failure {
+            None if mem::take(&mut self.full_reload) => NotesMessage::FullReload,
+            None => NotesMessage::Reload { changes },
+            Some(diagnostic) => NotesMessage::BuildFailed {
+                diagnostics: vec![diagnostic],
+            },
+        };
+
+        let _ = self.broadcast.send(message);
+
+        worked
+    }
+
+    /// Keep our fs watcher in line with what our build used, without loading our book again
+    fn update_watch_set(&mut self, built: &Built, changed_paths: &[PathBuf]) {
+        let watch_set = match built {
+            Built::Full(book) => WatchSet::for_book(book),
+            // only chapters changed, so only what they include could have
+            Built::InPlace => {
+                let mut watch_set = self.watch_set.clone();
+                for chapter in changed_paths {
+                    watch_set.add_includes_of(chapter);
+                }
+
+                watch_set
+            }
+        };
+
+        if watch_set != self.watch_set {
+            self.watch_set = watch_set.clone();
+            // our watcher is gone once our book is
+            let _ = self.watch_sets.send(watch_set);
         }
-    } else {
-        paths.count() > 0
     }
 }
 
-fn build_book(book_dir: &PathBuf, livereload_url: &str) -> Result<MDBook, MDBookError> {
-    let mut book = MDBook::load(&book_dir)?;
+/// Where our book's sources come from, which moves if `book.toml` points somewhere else
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct BookLayout {
+    source_dir: PathBuf,
+    theme_dir: PathBuf,
+}
+
+impl BookLayout {
+    fn of(book: &MDBook) -> BookLayout {
+        BookLayout {
+            source_dir: book.source_dir(),
+            theme_dir: book.theme_dir(),
+        }
+    }
+
+    /// Read our layout straight from `book.toml`, `None` if it's invalid
+    fn load(book_dir: &Path) -> Option<BookLayout> {
+        let config_path = book_dir.join("book.toml");
+        // mdbook uses its defaults for books without a config
+        let config = if config_path.exists() {
+            Config::from_disk(&config_path).ok()?
+        } else {
+            Config::default()
+        };
+
+        Some(BookLayout {
+            source_dir: book_dir.join(&config.book.src),
+            theme_dir: config
+                .html_config()
+                .unwrap_or_default()
+                .theme_dir(&book_dir.to_path_buf()),
+        })
+    }
+}
+
+/// Describe a changed source file for our clients. Files in our source directory are served
+/// from the same place in our output, with chapters rendered to html.
+fn change_for(path: &Path, book_dir: &Path, source_dir: &Path, slug: &str) -> Change {
+    let url = path.strip_prefix(source_dir).ok().map(|relative| {
+        let served = if relative.extension().is_some_and(|ext| ext == "md") {
+            relative.with_extension("html")
+        } else {
+            relative.to_path_buf()
+        };
+
+        let segments: Vec<_> = served
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect();
+
+        format!("/{}/static/{}", slug, segments.join("/"))
+    });
+
+    Change {
+        path: path
+            .strip_prefix(book_dir)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .into_owned(),
+        url,
+    }
+}
+
+/// The file mdbook's error chain says it failed on, relative to the book root. Only the errors
+/// that name their file are recognized, anything else is `None` rather than a guess.
+fn failing_file(causes: &[String], book_dir: &Path, source_dir: &Path) -> Option<String> {
+    // the innermost errors are the most specific about what went wrong
+    let path = causes.iter().rev().find_map(|cause| {
+        if cause == "Invalid configuration file" || cause == "Unable to open the configuration file"
+        {
+            Some(book_dir.join("book.toml"))
+        } else if cause == "Couldn't open SUMMARY.md" || cause == "Summary parsing failed" {
+            Some(source_dir.join("SUMMARY.md"))
+        } else if let Some(chapter) = cause.strip_prefix("Chapter file not found, ") {
+            Some(source_dir.join(chapter))
+        } else if cause.starts_with("Unable to read ")
+            || cause.starts_with("Could not read file for link ")
+        {
+            // these end with the full path in parentheses
+            let start = cause.rfind(" (")?;
+            cause[start + 2..].strip_suffix(')').map(PathBuf::from)
+        } else {
+            None
+        }
+    })?;
+
+    Some(
+        path.strip_prefix(book_dir)
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
+
+/// Everything we need to build our book
+struct NotesBuilder {
+    id: NotesId,
+    book_dir: PathBuf,
+    livereload_url: String,
+    /// Where to build instead of the book's own build directory
+    build_dir: Option<PathBuf>,
+    isolation: BuildIsolation,
+    events: NotesEvents,
+    /// Stops a worker's build once our notes are closed
+    queue: Arc<BuildQueue>,
+}
+
+impl NotesBuilder {
+    fn build(&self) -> Result<MDBook, MdNotesError> {
+        self.emitting(|| match self.run(None, None)? {
+            Built::Full(book) => Ok(*book),
+            // nothing is re-rendered in place without changed paths, but loading is harmless
+            Built::InPlace => self.request(None).load(),
+        })
+    }
+
+    /// Build our book from scratch and pick up its output
+    fn build_into(&self, output: &NotesOutput) -> Result<Built, MdNotesError> {
+        self.emitting(|| update_output(self.run(None, Some(output))?, output))
+    }
+
+    /// Rebuild our book after these paths changed, only re-rendering the changed chapters if
+    /// that's all that changed
+    fn rebuild(
+        &self,
+        changed_paths: &[PathBuf],
+        output: &NotesOutput,
+    ) -> Result<Built, MdNotesError> {
+        // a worker can only re-render chapters into a directory we share with it
+        let changed_paths = match (&self.isolation, output) {
+            (BuildIsolation::Process { .. }, NotesOutput::Memory(_)) => None,
+            _ => Some(changed_paths),
+        };
+
+        self.emitting(|| update_output(self.run(changed_paths, Some(output))?, output))
+    }
 
-    book.config
-        .set("output.html.livereload-url", livereload_url)?;
+    fn run(
+        &self,
+        changed_paths: Option<&[PathBuf]>,
+        output: Option<&NotesOutput>,
+    ) -> Result<Built, MdNotesError> {
+        let request = self.request(changed_paths);
 
-    book.build()?;
+        match &self.isolation {
+            BuildIsolation::InProcess => request.run(output),
+            BuildIsolation::Process { worker, timeout } => {
+                request.run_isolated(worker, *timeout, || self.queue.is_shutdown())
+            }
+        }
+    }
+
+    fn request(&self, changed_paths: Option<&[PathBuf]>) -> BuildRequest {
+        BuildRequest {
+            book_dir: self.book_dir.clone(),
+            livereload_url: self.livereload_url.clone(),
+            build_dir: self.build_dir.clone(),
+            changed_paths: changed_paths.map(<[PathBuf]>::to_vec),
+        }
+    }
+
+    /// Run a build while letting our listener know how it went
+    fn emitting<T, F>(&self, build: F) -> Result<T, MdNotesError>
+    where
+        F: FnOnce() -> Result<T, MdNotesError>,
+    {
+        self.events.emit(self.id, NotesEvent::Loading);
+
+        let start = Instant::now();
+        let result = build();
+
+        match &result {
+            Ok(_) => self
+                .events
+                .emit(self.id, NotesEvent::Built(start.elapsed())),
+            Err(e) => self
+                .events
+                .emit(self.id, NotesEvent::BuildFailed(format!("{}", e))),
+        }
+
+        result
+    }
+}
+
+/// Pick up a full build, anything built in place is already in our output
+fn update_output(built: Built, output: &NotesOutput) -> Result<Built, MdNotesError> {
+    output.forget_compressed();
+
+    if let Built::Full(book) = &built {
+        output.update(book)?;
+    }
 
-    Ok(book)
+    Ok(built)
 }
@@ -0,0 +1,311 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use futures::{future, stream, StreamExt};
+use headers::{ETag, LastModified};
+use mdbook::MDBook;
+use warp::path;
+use warp::reject::{self, Rejection};
+use warp::reply::Response;
+
+use crate::compression::CompressionCache;
+use crate::protocol::Diagnostic;
+use crate::warp_fs::Conditionals;
+use crate::{overlay, warp_fs};
+use crate::{BuildOutput, MdNotesError};
+
+/// Where the rendered html of our notes is served from
+#[derive(Clone)]
+pub enum NotesOutput {
+    /// mdbook's html output directory
+    Dir(PathBuf),
+    /// Our html output read into memory
+    Memory(Arc<RwLock<MemoryFiles>>),
+}
+
+/// Rendered files by their `/` separated path relative to our html output
+#[derive(Default)]
+pub struct MemoryFiles {
+    files: HashMap<String, MemoryFile>,
+    /// Every directory our files are in, so that requests for them find their index
+    dirs: HashSet<String>,
+    compressed: Arc<CompressionCache>,
+}
+
+#[derive(Clone)]
+struct MemoryFile {
+    contents: Bytes,
+    etag: ETag,
+    /// When these contents first turned up in our output, unchanged files keep their time
+    /// across builds so that clients can keep their cached copies
+    modified: SystemTime,
+}
+
+impl MemoryFile {
+    fn new(contents: Bytes, previous: Option<&MemoryFile>) -> MemoryFile {
+        let etag = warp_fs::content_etag(&contents);
+        let modified = match previous {
+            Some(previous) if previous.etag == etag => previous.modified,
+            _ => SystemTime::now(),
+        };
+
+        MemoryFile {
+            contents,
+            etag,
+            modified,
+        }
+    }
+}
+
+impl NotesOutput {
+    pub fn new(book: &MDBook, build_output: &BuildOutput) -> Result<NotesOutput, MdNotesError> {
+        match build_output {
+            BuildOutput::Book | BuildOutput::Cache(_) => {
+                Ok(NotesOutput::Dir(book.build_dir_for("html")))
+            }
+            BuildOutput::Memory => {
+                let output = NotesOutput::Memory(Default::default());
+                output.update(book)?;
+
+                Ok(output)
+            }
+        }
+    }
+
+    /// Pick up the output of a new build
+    pub fn update(&self, book: &MDBook) -> Result<(), MdNotesError> {
+        if let NotesOutput::Memory(memory) = self {
+            let mut contents = HashMap::new();
+            read_files(&book.build_dir_for("html"), "", &mut contents)?;
+
+            // our scratch directory is only needed until we've read it in
+            let build_dir = book.root.join(&book.config.build.build_dir);
+            if let Err(e) = fs::remove_dir_all(&build_dir) {
+                warn!("Couldn't remove our scratch build {:?}: {}", build_dir, e);
+            }
+
+            let mut memory = memory
+                .write()
+                .expect("Our memory output lock should never be poisoned");
+            let files: HashMap<_, _> = contents
+                .into_iter()
+                .map(|(key, contents)| {
+                    let file = MemoryFile::new(contents, memory.files.get(&key));
+                    (key, file)
+                })
+                .collect();
+            let mut dirs = HashSet::new();
+            for key in files.keys() {
+                add_dirs(&mut dirs, key);
+            }
+            *memory = MemoryFiles {
+                files,
+                dirs,
+                compressed: Default::default(),
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Drop what we compressed from our last build, once a new one has replaced it or our notes
+    /// are closed
+    pub fn forget_compressed(&self) {
+        match self {
+            NotesOutput::Dir(html_dir) => {
+                // files on disk are compressed by their canonical path
+                let html_dir = fs::canonicalize(html_dir).unwrap_or_else(|_| html_dir.clone());
+
+                warp_fs::dir_compression_cache()
+                    .remove_prefix(&format!("{}/", html_dir.to_string_lossy()))
+            }
+            NotesOutput::Memory(memory) => memory
+                .read()
+                .expect("Our memory output lock should never be poisoned")
+                .compressed
+                .remove_prefix(""),
+        }
+    }
+
+    /// Read a file from our output by its `/` separated path
+    pub fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        match self {
+            NotesOutput::Dir(html_dir) => fs::read(html_dir.join(path)),
+            NotesOutput::Memory(memory) => memory
+                .read()
+                .expect("Our memory output lock should never be poisoned")
+                .files
+                .get(path)
+                .map(|file| file.contents.to_vec())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, path.to_string())),
+        }
+    }
+
+    /// Replace a file in our output by its `/` separated path
+    pub fn write(&self, path: &str, contents: Vec<u8>) -> io::Result<()> {
+        match self {
+            NotesOutput::Dir(html_dir) => fs::write(html_dir.join(path), contents),
+            NotesOutput::Memory(memory) => {
+                let mut memory = memory
+                    .write()
+                    .expect("Our memory output lock should never be poisoned");
+                let file = MemoryFile::new(Bytes::from(contents), memory.files.get(path));
+                memory.files.insert(path.to_string(), file);
+                add_dirs(&mut memory.dirs, path);
+                memory.compressed.remove(path);
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Serve a file from our output, under an overlay if our last build failed
+    pub async fn serve(
+        &self,
+        tail: path::Tail,
+        build_failure: Option<Diagnostic>,
+        conditionals: Conditionals,
+    ) -> Result<Response, Rejection> {
+        match (self, build_failure) {
+            (NotesOutput::Dir(html_dir), Some(diagnostic)) => {
+                overlay::serve_with_overlay(html_dir, tail, &diagnostic, &conditionals).await
+            }
+            (NotesOutput::Dir(html_dir), None) => {
+                warp_fs::serve_file(html_dir, tail, &conditionals).await
+            }
+            (NotesOutput::Memory(memory), build_failure) => {
+                // take what we need and let go, so that we don't hold up our next build
+                let (key, file, compressed) = {
+                    let memory = memory
+                        .read()
+                        .expect("Our memory output lock should never be poisoned");
+                    let key = memory.key(tail.as_str())?;
+                    let file = memory.files.get(&key).cloned();
+
+                    (key, file, memory.compressed.clone())
+                };
+
+                serve_memory_file(
+                    &key,
+                    file,
+                    compressed,
+                    build_failure.as_ref(),
+                    &conditionals,
+                )
+                .await
+            }
+        }
+    }
+}
+
+impl MemoryFiles {
+    /// The key of the file a request for `tail` should be answered with
+    fn key(&self, tail: &str) -> Result<String, Rejection> {
+        let relative = warp_fs::sanitize_path(Path::new(""), tail)?;
+        let key = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        // directories are served by their index
+        if key.is_empty() || self.is_dir(&key) {
+            Ok(format!("{}/index.html", key)
+                .trim_start_matches('/')
+                .to_string())
+        } else {
+            Ok(key)
+        }
+    }
+
+    fn is_dir(&self, key: &str) -> bool {
+        !self.files.contains_key(key) && self.dirs.contains(key)
+    }
+}
+
+/// Add each directory above the file with this key
+fn add_dirs(dirs: &mut HashSet<String>, key: &str) {
+    for (end, _) in key.match_indices('/') {
+        dirs.insert(key[..end].to_string());
+    }
+}
+
+async fn serve_memory_file(
+    key: &str,
+    file: Option<MemoryFile>,
+    compressed: Arc<CompressionCache>,
+    build_failure: Option<&Diagnostic>,
+    conditionals: &Conditionals,
+) -> Result<Response, Rejection> {
+    let is_html = key.ends_with(".html");
+
+    match (file, build_failure) {
+        (file, Some(diagnostic)) if is_html => Ok(overlay::overlay_reply(
+            file.as_ref().map(|file| file.contents.as_ref()),
+            diagnostic,
+        )),
+        (Some(file), _) => {
+            let mime = mime_guess::from_path(key).first_or_octet_stream();
+            let contents = file.contents;
+
+            if let Some(encoding) = conditionals.encoding(&mime, contents.len() as u64) {
+                let uncompressed = contents.clone();
+                let compressed = compressed
+                    .get_or_compress_blocking(key.to_string(), file.etag.clone(), encoding, || {
+                        Ok(uncompressed)
+                    })
+                    .await;
+
+                match compressed {
+                    Ok(compressed) => {
+                        return Ok(warp_fs::encoded_reply(
+                            conditionals,
+                            compressed,
+                            encoding,
+                            mime,
+                            Some(LastModified::from(file.modified)),
+                            &file.etag,
+                        ))
+                    }
+                    Err(e) => warn!("Couldn't compress {:?} with {:?}: {}", key, encoding, e),
+                }
+            }
+
+            Ok(warp_fs::conditional_reply(
+                conditionals,
+                contents.len() as u64,
+                mime,
+                Some(LastModified::from(file.modified)),
+                Some(file.etag),
+                |start, end| {
+                    let part = contents.slice(start as usize..end as usize);
+                    stream::once(future::ok(part)).boxed()
+                },
+            ))
+        }
+        (None, _) => {
+            debug!("file not found in memory: {:?}", key);
+            Err(reject::not_found())
+        }
+    }
+}
+
+fn read_files(dir: &Path, prefix: &str, files: &mut HashMap<String, Bytes>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let key = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+
+        if entry.file_type()?.is_dir() {
+            read_files(&entry.path(), &format!("{}/", key), files)?;
+        } else {
+            files.insert(key, Bytes::from(fs::read(entry.path())?));
+        }
+    }
+
+    Ok(())
+}
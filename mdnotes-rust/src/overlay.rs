@@ -0,0 +1,125 @@
+use std::io;
+use std::path::Path;
+
+use headers::{CacheControl, ContentLength, ContentType, HeaderMapExt};
+use warp::hyper::Body;
+use warp::path;
+use warp::reject::{self, Rejection};
+use warp::reply::Response;
+
+use crate::protocol::Diagnostic;
+use crate::warp_fs::{self, Conditionals};
+
+const OVERLAY_ID: &str = "mdnotes-build-error";
+
+/// Serve our notes while their last build is broken. Pages get an overlay explaining what went
+/// wrong on top of the output from the last good build. Our livereload message reloads the page
+/// after every build, so the overlay goes away as soon as the book builds again.
+pub async fn serve_with_overlay(
+    html_dir: &Path,
+    tail: path::Tail,
+    diagnostic: &Diagnostic,
+    conditionals: &Conditionals,
+) -> Result<Response, Rejection> {
+    let file_path = warp_fs::resolve_file(html_dir, tail).await?;
+
+    let is_html = file_path.extension().is_some_and(|ext| ext == "html");
+    if !is_html {
+        return warp_fs::file_reply(file_path, conditionals).await;
+    }
+
+    match tokio::fs::read(&file_path).await {
+        Ok(html) => Ok(overlay_reply(Some(&html), diagnostic)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(overlay_reply(None, diagnostic)),
+        Err(e) => {
+            log::error!("file open error (path={:?}): {} ", file_path, e);
+            Err(reject::not_found())
+        }
+    }
+}
+
+/// Reply with a page from our last good build under our overlay, or with an error page if that
+/// build never made it
+pub fn overlay_reply(html: Option<&[u8]>, diagnostic: &Diagnostic) -> Response {
+    let html = match html {
+        Some(html) => inject_overlay(&String::from_utf8_lossy(html), diagnostic),
+        None => inject_overlay(&error_page(diagnostic), diagnostic),
+    };
+
+    let mut resp = Response::new(Body::from(html.clone()));
+    resp.headers_mut()
+        .typed_insert(ContentLength(html.len() as u64));
+    resp.headers_mut().typed_insert(ContentType::html());
+    // this page is only good until the next build
+    resp.headers_mut()
+        .typed_insert(CacheControl::new().with_no_store());
+
+    resp
+}
+
+/// Put our overlay at the end of the page's body so that it sits on top of everything else
+fn inject_overlay(html: &str, diagnostic: &Diagnostic) -> String {
+    let overlay = render_overlay(diagnostic);
+
+    match html.rfind("</body>") {
+        Some(end) => format!("{}{}{}", &html[..end], overlay, &html[end..]),
+        None => format!("{}{}", html, overlay),
+    }
+}
+
+/// A page to stand in for output our last good build never made
+fn error_page(diagnostic: &Diagnostic) -> String {
+    let title = match &diagnostic.file {
+        Some(file) => format!("Couldn't build {}", file),
+        None => "Couldn't build the book".to_string(),
+    };
+
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"UTF-8\"><title>{}</title></head><body></body></html>\n",
+        escape_html(&title)
+    )
+}
+
+fn render_overlay(diagnostic: &Diagnostic) -> String {
+    let heading = match &diagnostic.file {
+        Some(file) => format!("The book failed to build at {}", file),
+        None => "The book failed to build".to_string(),
+    };
+
+    let causes: String = diagnostic
+        .causes
+        .iter()
+        .map(|cause| format!("<li>{}</li>", escape_html(cause)))
+        .collect();
+
+    format!(
+        "<div id=\"{id}\" style=\"position: fixed; inset: 0; z-index: 10000; overflow: auto; \
+         padding: 2em; background: rgba(20, 20, 20, 0.92); color: #f8f8f2; \
+         font-family: monospace; font-size: 14px;\">\
+         <h2 style=\"color: #ff6e6e;\">{heading}</h2>\
+         <ol>{causes}</ol>\
+         <p style=\"opacity: 0.7;\">Showing the last successful build. \
+         This will go away once the book builds again.</p>\
+         <button onclick=\"document.getElementById('{id}').remove()\">Dismiss</button>\
+         </div>",
+        id = OVERLAY_ID,
+        heading = escape_html(&heading),
+        causes = causes,
+    )
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use std::{fs, mem};
+
+use notify::DebouncedEvent;
+
+use crate::watcher::NotesWatcher;
+
+/// Watches for changes by comparing snapshots of the files we watch at an interval, for network
+/// shares and FUSE mounts that never send file events. Changes are sent as the same events our
+/// native watcher sends.
+pub struct PollWatcher {
+    /// What our watched paths looked like when we last checked them
+    last: Arc<Mutex<Snapshot>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl PollWatcher {
+    pub fn new(sender: Sender<DebouncedEvent>, interval: Duration) -> PollWatcher {
+        let last = Arc::new(Mutex::new(Snapshot::take(&BTreeMap::new())));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let poll_last = last.clone();
+        let poll_stop = stop.clone();
+        thread::spawn(move || {
+            while !poll_stop.load(Ordering::Relaxed) {
+                thread::sleep(interval);
+
+                let events = {
+                    let mut last = lock(&poll_last);
+                    let snapshot = Snapshot::take(&last.roots);
+
+                    mem::replace(&mut *last, snapshot).changes(&last)
+                };
+                for event in events {
+                    if sender.send(event).is_err() {
+                        // nobody is listening anymore
+                        return;
+                    }
+                }
+            }
+        });
+
+        PollWatcher { last, stop }
+    }
+}
+
+impl NotesWatcher for PollWatcher {
+    fn watch(&mut self, path: &Path, recursive: bool) -> Result<(), notify::Error> {
+        if !path.exists() {
+            return Err(notify::Error::PathNotFound);
+        }
+
+        // anything that changes from here on is a change, even before our next poll
+        lock(&self.last).watch(path, recursive);
+
+        Ok(())
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Result<(), notify::Error> {
+        match lock(&self.last).roots.remove(path) {
+            Some(_) => Ok(()),
+            None => Err(notify::Error::WatchNotFound),
+        }
+    }
+}
+
+impl Drop for PollWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+fn lock(last: &Mutex<Snapshot>) -> std::sync::MutexGuard<'_, Snapshot> {
+    last.lock()
+        .expect("Our poll snapshot lock should never be poisoned")
+}
+
+/// The size and modification time of every file under a set of roots
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    roots: BTreeMap<PathBuf, bool>,
+    files: BTreeMap<PathBuf, (u64, Option<SystemTime>)>,
+}
+
+impl Snapshot {
+    pub fn take(roots: &BTreeMap<PathBuf, bool>) -> Snapshot {
+        let mut files = BTreeMap::new();
+        for (root, recursive) in roots {
+            add_files(&mut files, root, *recursive);
+        }
+
+        Snapshot {
+            roots: roots.clone(),
+            files,
+        }
+    }
+
+    /// Add a root to our snapshot as it looks right now
+    pub fn watch(&mut self, root: &Path, recursive: bool) {
+        self.roots.insert(root.to_path_buf(), recursive);
+        add_files(&mut self.files, root, recursive);
+    }
+
+    /// The events that turn us into a newer snapshot. Only roots in both snapshots are compared,
+    /// so newly watched paths don't look like they were just created.
+    pub fn changes(&self, newer: &Snapshot) -> Vec<DebouncedEvent> {
+        let in_both = |path: &Path| {
+            let under = |roots: &BTreeMap<PathBuf, bool>| {
+                roots.iter().any(|(root, recursive)| {
+                    (*recursive && path.starts_with(root)) || path.parent() == Some(root)
+                })
+            };
+
+            under(&self.roots) && under(&newer.roots)
+        };
+
+        let mut events = vec![];
+        for (path, stamp) in &newer.files {
+            if !in_both(path) {
+                continue;
+            }
+
+            match self.files.get(path) {
+                None => events.push(DebouncedEvent::Create(path.clone())),
+                Some(old_stamp) if old_stamp != stamp => {
+                    events.push(DebouncedEvent::Write(path.clone()))
+                }
+                Some(_) => (),
+            }
+        }
+
+        for path in self.files.keys() {
+            if in_both(path) && !newer.files.contains_key(path) {
+                events.push(DebouncedEvent::Remove(path.clone()));
+            }
+        }
+
+        events
+    }
+}
+
+fn add_files(
+    files: &mut BTreeMap<PathBuf, (u64, Option<SystemTime>)>,
+    dir: &Path,
+    recursive: bool,
+) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        // it was removed, so everything in it shows up as removed
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => {
+                if recursive {
+                    add_files(files, &path, recursive);
+                }
+            }
+            Ok(metadata) => {
+                files.insert(path, (metadata.len(), metadata.modified().ok()));
+            }
+            Err(_) => (),
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+
+use crate::MdNotesError;
+
+/// The version of our JSON websocket protocol, sent with every message
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Messages sent to the websocket clients viewing a set of notes
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum NotesMessage {
+    /// A build finished and these source files changed
+    Reload {
+        changes: Vec<Change>,
+    },
+    /// Anything in the book could have changed, like after its source or theme moved
+    FullReload,
+    BuildStarted,
+    BuildFailed {
+        diagnostics: Vec<Diagnostic>,
+    },
+    /// We couldn't keep watching the book, so what's shown may be out of date
+    WatchFailed {
+        message: String,
+    },
+    /// The book's directory was removed, so it won't build again
+    NotebookOrphaned,
+    NotebookClosed,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Change {
+    /// The changed source path, relative to the book root
+    pub path: String,
+    /// Where the rendered file is served, or `None` if the change affects the whole book
+    pub url: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    pub message: String,
+    /// The file the build failed on, relative to the book root, if mdbook's error named it
+    pub file: Option<String>,
+    /// The mdbook error chain, starting with the outermost error
+    pub causes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn from_error(error: &MdNotesError, file: Option<String>) -> Diagnostic {
+        Diagnostic {
+            message: error.to_string(),
+            file,
+            causes: error.causes(),
+        }
+    }
+}
+
+/// Which protocol a websocket client speaks, picked with the `protocol` query parameter
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// mdbook's stock livereload script, which only understands a bare `"reload"`
+    #[default]
+    Livereload,
+    Json,
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    version: u32,
+    #[serde(flatten)]
+    message: &'a NotesMessage,
+}
+
+impl NotesMessage {
+    /// Encode this message for a client, or `None` if the client doesn't care about it
+    pub fn encode(&self, protocol: Protocol) -> Option<String> {
+        match protocol {
+            Protocol::Json => Some(
+                serde_json::to_string(&Envelope {
+                    version: PROTOCOL_VERSION,
+                    message: self,
+                })
+                .expect("Our messages should always serialize"),
+            ),
+            Protocol::Livereload => match self {
+                // a failed build is still a change the stock script should show
+                NotesMessage::Reload { .. }
+                | NotesMessage::FullReload
+                | NotesMessage::BuildFailed { .. } => Some("reload".to_string()),
+                NotesMessage::BuildStarted
+                | NotesMessage::WatchFailed { .. }
+                | NotesMessage::NotebookOrphaned
+                | NotesMessage::NotebookClosed => None,
+            },
+        }
+    }
+}
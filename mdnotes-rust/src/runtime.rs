@@ -1,102 +1,158 @@
 use core::mem;
-use core::sync::atomic::AtomicU8;
+use core::sync::atomic::AtomicU64;
 use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::sync::atomic::Ordering;
-use std::sync::{Arc, Once};
+use std::sync::{Arc, Mutex, MutexGuard, Once};
 use std::thread;
 
 use dashmap::DashMap;
 use env_logger::Env;
 use futures::channel::oneshot;
 use futures::channel::oneshot::Sender;
-use futures::SinkExt;
+use futures::{FutureExt, SinkExt};
+use http::HeaderMap;
+use serde::Deserialize;
 use tokio::runtime::Runtime;
 use tokio::sync::broadcast::RecvError;
 use warp::ws::Message;
 use warp::{path, Filter, Reply};
 
-use crate::mdnotes::MdNotes;
-use crate::{warp_fs, MdNotesError};
+use crate::build_queue::BuildLimiter;
+use crate::events::{NotesEvent, NotesEventListener, NotesEvents};
+use crate::mdnotes::{self, MdNotes, NotesContext};
+use crate::protocol::Protocol;
+use crate::warp_fs::Conditionals;
+use crate::{EnvironmentSetup, MdNotesError, MdNotesRuntimeBuilder, MdNotesRuntimeConfig, NotesId};
 
-static STARTUP: Once = Once::new();
+static INIT_LOGGER: Once = Once::new();
+static SETUP_ENVIRONMENT: Once = Once::new();
 
 pub struct MdNotesRuntime {
-    note_inc: AtomicU8,
-    notes: Arc<DashMap<u8, MdNotes>>,
+    note_inc: AtomicU64,
+    notes: Arc<DashMap<NotesId, MdNotes>>,
+    /// The slugs our notes are mounted at in our server
+    mounts: Arc<DashMap<String, NotesId>>,
+    /// Our open books by their canonical path, so opening a book twice shares the same notes
+    open_books: Mutex<HashMap<PathBuf, OpenBook>>,
     server_address: SocketAddr,
     shutdown: Option<Sender<()>>,
+    events: NotesEvents,
+    build_limiter: BuildLimiter,
+    config: MdNotesRuntimeConfig,
+}
+
+#[derive(Deserialize)]
+struct WsQuery {
+    #[serde(default)]
+    protocol: Protocol,
+}
+
+struct OpenBook {
+    notes_id: NotesId,
+    ref_count: usize,
 }
 
 const PATH_ENV: &str = "PATH";
 
 impl MdNotesRuntime {
     pub fn new() -> Result<MdNotesRuntime, MdNotesError> {
-        STARTUP.call_once(|| {
-            env_logger::init_from_env(
-                Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"),
-            );
+        Self::with_config(MdNotesRuntimeConfig::default())
+    }
+
+    pub fn builder() -> MdNotesRuntimeBuilder {
+        MdNotesRuntimeBuilder::new()
+    }
+
+    pub fn with_config(config: MdNotesRuntimeConfig) -> Result<MdNotesRuntime, MdNotesError> {
+        if config.init_logger {
+            INIT_LOGGER.call_once(|| {
+                // someone else may have already set up a logger, which is fine
+                let _ = env_logger::try_init_from_env(
+                    Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"),
+                );
+            });
+        }
 
-            setup_environment();
-        });
+        if let EnvironmentSetup::LoginShells(shells) = &config.environment {
+            SETUP_ENVIRONMENT.call_once(|| setup_environment(shells));
+        }
 
-        let notes: Arc<DashMap<u8, MdNotes>> = Arc::new(DashMap::new());
+        let notes: Arc<DashMap<NotesId, MdNotes>> = Arc::new(DashMap::new());
+        let mounts: Arc<DashMap<String, NotesId>> = Arc::new(DashMap::new());
         let route_notes = notes.clone();
+        let route_mounts = mounts.clone();
 
         let static_route = warp::path::param()
             .and(warp::path("static"))
             .and(warp::path::tail())
-            .and_then(move |raw_notes_id: String, tail: path::Tail| {
+            .and(warp::header::headers_cloned())
+            .and_then(move |mount: String, tail: path::Tail, headers: HeaderMap| {
                 let route_notes = route_notes.clone();
+                let notes_id = resolve_mount(&route_mounts, &mount);
+                let conditionals = Conditionals::new(&headers);
 
                 async move {
-                    let notes_id = raw_notes_id.parse::<u8>().unwrap();
+                    let notes = notes_id.and_then(|notes_id| route_notes.get(&notes_id));
 
-                    match route_notes.get(&notes_id) {
-                        Some(note) => warp_fs::serve_file(&note.html_dir, tail).await,
+                    match notes {
+                        Some(note) => {
+                            let output = note.output.clone();
+                            let build_failure = note.build_failure();
+                            // drop our lock on the note
+                            mem::drop(note);
+
+                            output.serve(tail, build_failure, conditionals).await
+                        }
                         None => Err(warp::reject()),
                     }
                 }
             });
 
         let route_notes = notes.clone();
+        let route_mounts = mounts.clone();
 
         let ws_route = warp::path::param()
             .and(warp::path("ws"))
+            .and(warp::query::<WsQuery>())
             .and(warp::ws())
-            .map(move |raw_notes_id: String, ws: warp::ws::Ws| {
-                let notes_id = raw_notes_id.parse::<u8>().unwrap();
+            .map(move |mount: String, query: WsQuery, ws: warp::ws::Ws| {
+                let notes_id = resolve_mount(&route_mounts, &mount);
                 let route_notes = route_notes.clone();
 
                 ws.on_upgrade(move |mut websocket| async move {
-                    match route_notes.get(&notes_id) {
-                        Some(note) => {
-                            let mut receiver = note.get_ws_receiver();
-                            // drop our lock on the note
-                            mem::drop(note);
-
-                            loop {
-                                // wait for the not receiver to tell us to reload
-                                match receiver.recv().await {
-                                    Ok(event) => match websocket.send(Message::text(event)).await {
-                                        Ok(_) => (),
-                                        Err(e) => {
-                                            warn!("ws send error: {}", e);
-                                            break;
-                                        }
-                                    },
-                                    Err(RecvError::Lagged(_)) => (), // we don't care if we're lagging
-                                    Err(RecvError::Closed) => break, // we're done broadcasting so break out
+                    if let Some(note) = notes_id.and_then(|notes_id| route_notes.get(&notes_id)) {
+                        let mut receiver = note.get_ws_receiver();
+                        // drop our lock on the note
+                        mem::drop(note);
+
+                        loop {
+                            // wait for the not receiver to tell us to reload
+                            match receiver.recv().await {
+                                Ok(message) => {
+                                    let encoded = match message.encode(query.protocol) {
+                                        Some(encoded) => encoded,
+                                        None => continue,
+                                    };
+
+                                    if let Err(e) = websocket.send(Message::text(encoded)).await {
+                                        warn!("ws send error: {}", e);
+                                        break;
+                                    }
                                 }
+                                Err(RecvError::Lagged(_)) => (), // we don't care if we're lagging
+                                Err(RecvError::Closed) => break, // we're done broadcasting so break out
                             }
                         }
-                        None => match websocket.close().await {
-                            Ok(_) => (),
-                            Err(e) => log::warn!("Error closing web socket: {}", e),
-                        },
-                    };
+                    }
+
+                    // let our clients know we're done rather than just dropping the connection
+                    if let Err(e) = websocket.close().await {
+                        log::warn!("Error closing web socket: {}", e);
+                    }
 
                     println!("done with route get");
                 })
@@ -104,93 +160,327 @@ impl MdNotesRuntime {
 
         let routes = static_route.or(ws_route);
 
-        let (address, shutdown) = spawn_background_server(routes)?;
+        let (address, shutdown) = spawn_background_server(routes, &config)?;
+        config.port.remember_port(address.port());
 
         Ok(MdNotesRuntime {
-            note_inc: AtomicU8::new(1),
+            note_inc: AtomicU64::new(1),
             notes,
+            mounts,
+            open_books: Mutex::new(HashMap::new()),
             server_address: address,
             shutdown: Some(shutdown),
+            events: NotesEvents::default(),
+            build_limiter: BuildLimiter::new(config.max_concurrent_builds),
+            config,
         })
     }
 
+    /// The address our server is bound to
+    pub fn server_address(&self) -> SocketAddr {
+        self.server_address
+    }
+
     pub fn server_port(&self) -> u16 {
         self.server_address.port()
     }
 
-    pub fn open_notes(&self, book_dir: PathBuf) -> u8 {
-        let notes_id = self.note_inc.fetch_add(1, Ordering::Relaxed);
+    pub fn config(&self) -> &MdNotesRuntimeConfig {
+        &self.config
+    }
+
+    /// Listen for the lifecycle events of all of our notes. The listener is called from background
+    /// threads and replaces any previous listener, once any calls to it have finished.
+    ///
+    /// Events start once `open_notes` has returned the notes' id, and `Closed` is always their
+    /// last. Notes that couldn't be opened still report their failed build under the id they
+    /// would have had.
+    pub fn set_event_listener<F>(&self, listener: F)
+    where
+        F: Fn(NotesId, &NotesEvent) + Send + Sync + 'static,
+    {
+        let listener: NotesEventListener = Arc::new(listener);
+
+        self.events.set_listener(Some(listener));
+    }
+
+    /// Stop sending events, once any calls to our listener have finished
+    pub fn clear_event_listener(&self) {
+        self.events.set_listener(None);
+    }
+
+    /// Open the book at `book_dir`. If the book is already open we return the existing notes id,
+    /// and the notes stay open until every open has been matched with a close.
+    pub fn open_notes(&self, book_dir: PathBuf) -> Result<NotesId, MdNotesError> {
+        let book_dir = fs::canonicalize(book_dir)?;
+
+        // reserve our id and slug, but don't hold up other books while we build
+        let (notes_id, slug) = {
+            let mut open_books = self.lock_open_books();
+            if let Some(notes_id) = reuse_open_book(&mut open_books, &book_dir) {
+                return Ok(notes_id);
+            }
+
+            let notes_id = self.note_inc.fetch_add(1, Ordering::Relaxed);
+            let slug = self.unique_slug(mdnotes::book_slug(&book_dir));
+            // our notes aren't served until they're built, but no one else can take our slug
+            self.mounts.insert(slug.clone(), notes_id);
+            // nobody knows our id until we've returned it
+            self.events.pending(notes_id);
+
+            (notes_id, slug)
+        };
 
         info!(
-            "Loading notes: {} @ {}",
+            "Loading notes: {} ({}) @ {}",
             notes_id,
+            slug,
             book_dir.to_string_lossy()
         );
 
-        let notes = MdNotes::new(notes_id, book_dir, self.server_port()).unwrap();
+        let notes = match MdNotes::new(
+            notes_id,
+            slug.clone(),
+            book_dir.clone(),
+            self.notes_context(),
+        ) {
+            Ok(notes) => notes,
+            Err(e) => {
+                self.mounts.remove(&slug);
+                self.events.failed_to_open(notes_id);
+
+                return Err(e);
+            }
+        };
+
+        let mut open_books = self.lock_open_books();
+        // someone else opened the same book while we were building it, so use theirs
+        if let Some(existing_id) = reuse_open_book(&mut open_books, &book_dir) {
+            mem::drop(open_books);
+            self.mounts.remove(&slug);
+            // nobody ever saw these notes, so they close without any events
+            mem::drop(notes);
+
+            return Ok(existing_id);
+        }
 
         self.notes.insert(notes_id, notes);
+        open_books.insert(
+            book_dir,
+            OpenBook {
+                notes_id,
+                ref_count: 1,
+            },
+        );
+        mem::drop(open_books);
+
+        self.events.register(notes_id);
 
-        notes_id
+        Ok(notes_id)
     }
 
-    pub fn close_notes(&self, note_id: u8) {
-        if let Some((_, notes)) = self.notes.remove(&note_id) {
+    /// The slug our notes are mounted at, they're served from `/{slug}/static/`. The notes id
+    /// can be used in place of the slug too.
+    pub fn notes_slug(&self, notes_id: NotesId) -> Option<String> {
+        self.notes.get(&notes_id).map(|notes| notes.slug.clone())
+    }
+
+    /// Whether the notes' book directory was removed, `None` if they aren't open. Orphaned notes
+    /// keep serving their last build until they're closed.
+    pub fn notes_orphaned(&self, notes_id: NotesId) -> Option<bool> {
+        self.notes.get(&notes_id).map(|notes| notes.is_orphaned())
+    }
+
+    pub fn close_notes(&self, note_id: NotesId) -> Result<(), MdNotesError> {
+        let mut open_books = self.lock_open_books();
+
+        let book_dir = match open_books
+            .iter_mut()
+            .find(|(_, open_book)| open_book.notes_id == note_id)
+        {
+            Some((book_dir, open_book)) => {
+                open_book.ref_count -= 1;
+
+                if open_book.ref_count > 0 {
+                    debug!("Notes {} are still open elsewhere", note_id);
+
+                    return Ok(());
+                }
+
+                book_dir.clone()
+            }
+            None => {
+                warn!("Tried to close invalid note_id: {}", note_id);
+
+                return Err(MdNotesError::InvalidNotesId(note_id));
+            }
+        };
+
+        open_books.remove(&book_dir);
+        let notes = self.notes.remove(&note_id);
+        // our listener may call back into us once our notes are dropped
+        mem::drop(open_books);
+
+        if let Some((_, notes)) = notes {
+            self.mounts.remove(&notes.slug);
             mem::drop(notes);
+        }
+
+        info!("Closed notes: {}", note_id);
+
+        Ok(())
+    }
 
-            info!("Closed notes: {}", note_id);
-        } else {
-            warn!("Tried to close invalid note_id: {}", note_id);
+    fn lock_open_books(&self) -> MutexGuard<'_, HashMap<PathBuf, OpenBook>> {
+        self.open_books
+            .lock()
+            .expect("Our lock should never be poisoned")
+    }
+
+    fn notes_context(&self) -> NotesContext {
+        // our pages are viewed from this machine, so prefer localhost unless we're bound to a
+        // specific remote address
+        let host = match self.server_address.ip() {
+            ip if ip.is_loopback() || ip.is_unspecified() => "localhost".to_string(),
+            IpAddr::V6(ip) => format!("[{}]", ip),
+            ip => ip.to_string(),
+        };
+
+        NotesContext {
+            server_host: format!("{}:{}", host, self.server_port()),
+            broadcast_buffer: self.config.broadcast_buffer,
+            events: self.events.clone(),
+            build_output: self.config.build_output.clone(),
+            build_limiter: self.build_limiter.clone(),
+            build_isolation: self.config.build_isolation.clone(),
+            watcher: self.config.watcher.clone(),
+            watch_mode: self.config.watch_mode,
+            poll_interval: self.config.poll_interval,
         }
     }
+
+    /// Find a slug that isn't already mounted by adding a suffix to our preferred slug
+    fn unique_slug(&self, slug: String) -> String {
+        if !self.mounts.contains_key(&slug) {
+            return slug;
+        }
+
+        (2..)
+            .map(|suffix| format!("{}-{}", slug, suffix))
+            .find(|candidate| !self.mounts.contains_key(candidate))
+            .expect("We'll always find an unused suffix")
+    }
+}
+
+/// Share the notes of a book that's already open, `None` if it isn't
+fn reuse_open_book(
+    open_books: &mut HashMap<PathBuf, OpenBook>,
+    book_dir: &Path,
+) -> Option<NotesId> {
+    let open_book = open_books.get_mut(book_dir)?;
+    open_book.ref_count += 1;
+
+    info!(
+        "Reusing notes: {} @ {}",
+        open_book.notes_id,
+        book_dir.to_string_lossy()
+    );
+
+    Some(open_book.notes_id)
+}
+
+/// Our notes are mounted at their slug, and at their notes id for older clients
+fn resolve_mount(mounts: &DashMap<String, NotesId>, mount: &str) -> Option<NotesId> {
+    mounts
+        .get(mount)
+        .map(|notes_id| *notes_id)
+        .or_else(|| mount.parse().ok())
 }
 
 impl Drop for MdNotesRuntime {
     fn drop(&mut self) {
+        // our notes can outlive us for a moment, but our listener can't
+        self.events.set_listener(None);
+
         // signal our server to shutdown
-        mem::replace(&mut self.shutdown, None).map(|shutdown| shutdown.send(()));
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
     }
 }
 
-fn spawn_background_server<F>(routes: F) -> Result<(SocketAddr, Sender<()>), MdNotesError>
+fn spawn_background_server<F>(
+    routes: F,
+    config: &MdNotesRuntimeConfig,
+) -> Result<(SocketAddr, Sender<()>), MdNotesError>
 where
     F: Filter + Clone + Send + Sync + 'static,
     F::Extract: Reply,
 {
     let (background_sender, foreground_receiver) = oneshot::channel();
     let (shutdown, shutdown_signal) = oneshot::channel::<()>();
+    // a failed bind consumes its shutdown signal, so share it between our attempts
+    let shutdown_signal = shutdown_signal.shared();
+    let bind_address = config.bind_address;
+    let preferred_port = config.port.preferred_port();
 
-    thread::spawn(|| {
+    thread::spawn(move || {
         let runtime_result = Runtime::new();
 
         match runtime_result {
             Ok(mut runtime) => {
-                let (address, startup) = runtime.enter(|| {
-                    warp::serve(routes).bind_with_graceful_shutdown(([127, 0, 0, 1], 0), async {
-                        shutdown_signal.await.ok();
-                    })
+                let bind = |port: u16| {
+                    let shutdown_signal = shutdown_signal.clone();
+
+                    warp::serve(routes.clone()).try_bind_with_graceful_shutdown(
+                        (bind_address, port),
+                        async {
+                            shutdown_signal.await.ok();
+                        },
+                    )
+                };
+
+                let bind_result = runtime.enter(|| {
+                    preferred_port
+                        .and_then(|port| match bind(port) {
+                            Ok(server) => Some(Ok(server)),
+                            Err(e) => {
+                                info!("Port {} isn't available, using a random port: {}", port, e);
+                                None
+                            }
+                        })
+                        .unwrap_or_else(|| bind(0))
                 });
 
-                info!("Server started up");
+                match bind_result {
+                    Ok((address, startup)) => {
+                        info!("Server started up");
 
-                background_sender
-                    .send(Ok(address))
-                    .expect("Our channel to the foreground should always be open");
+                        background_sender
+                            .send(Ok(address))
+                            .expect("Our channel to the foreground should always be open");
 
-                runtime.block_on(startup);
+                        runtime.block_on(startup);
 
-                info!("Server stopped, shutting down runtime");
+                        info!("Server stopped, shutting down runtime");
+                    }
+                    Err(e) => {
+                        background_sender
+                            .send(Err(MdNotesError::Server(format!("{}", e))))
+                            .expect("Our channel to the foreground should always be open");
+                    }
+                }
             }
             Err(e) => {
                 background_sender
-                    .send(Err(format!("{}", e)))
+                    .send(Err(MdNotesError::Server(format!("{}", e))))
                     .expect("Our channel to the foreground should always be open");
             }
         };
     });
 
-    let address =
-        futures::executor::block_on(foreground_receiver).map_err(|e| format!("{}", e))??;
+    let address = futures::executor::block_on(foreground_receiver)
+        .map_err(|e| MdNotesError::Server(format!("{}", e)))??;
 
     Ok((address, shutdown))
 }
@@ -198,11 +488,11 @@ where
 /// Mac applications get their environment from launchctl, but that kind of sucks. So attempt to
 /// load in our known possible environments and use their parameters instead.
 ///
-/// This prioritizes loading our environment in this order:
+/// This prioritizes loading our environment from each of our login shells in order, by default:
 /// * zsh
 /// * bash
 /// * fall back on setting the path for Cargo and Homebrew
-fn setup_environment() {
+fn setup_environment(shells: &[String]) {
     fn parse_environment(env_str: String, environment: &mut HashMap<String, String>) -> bool {
         let mut found = false;
         for raw_line in env_str.split('\n') {
@@ -222,7 +512,7 @@ fn setup_environment() {
         let mut environment = HashMap::new();
 
         // attempt to load our environment
-        for command in &["zsh", "bash"] {
+        for command in shells {
             match Command::new(command)
                 .arg("-c") // run the command
                 .arg("-l") // run as a login shell
@@ -1,15 +1,25 @@
 use std::cmp;
 use std::fs::Metadata;
-use std::io;
-use std::path::PathBuf;
+use std::io::{self, SeekFrom};
+use std::ops::Bound;
+use std::path::{Component, Path, PathBuf};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
 use std::task::Poll;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use bytes::{Bytes, BytesMut};
 use futures::future::Either;
+use futures::stream::BoxStream;
 use futures::{future, ready, stream, FutureExt, Stream, StreamExt};
-use headers::{AcceptRanges, ContentLength, ContentType, HeaderMapExt, LastModified};
-use mime_guess;
+use headers::{
+    AcceptRanges, ContentLength, ContentRange, ContentType, ETag, HeaderMapExt, IfModifiedSince,
+    IfNoneMatch, IfRange, LastModified, Range,
+};
+use http::header::{ACCEPT_ENCODING, ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_TYPE, VARY};
+use http::{HeaderMap, HeaderValue, StatusCode};
+use mime_guess::Mime;
 use tokio::fs::File as TkFile;
 use tokio::io::AsyncRead;
 use urlencoding::decode;
@@ -18,9 +28,23 @@ use warp::path;
 use warp::reject::{self, Rejection};
 use warp::reply::Response;
 
-/// Taken from: https://github.com/seanmonstar/warp/blob/master/src/filters/fs.rs
+use crate::compression::{is_compressible, CompressionCache, Encoding};
+use crate::config::fnv1a;
 
-pub async fn serve_file(path: &PathBuf, tail: path::Tail) -> Result<Response, Rejection> {
+// Taken from: https://github.com/seanmonstar/warp/blob/master/src/filters/fs.rs
+
+pub async fn serve_file(
+    path: &Path,
+    tail: path::Tail,
+    conditionals: &Conditionals,
+) -> Result<Response, Rejection> {
+    let file_path = resolve_file(path, tail).await?;
+
+    file_reply(file_path, conditionals).await
+}
+
+/// The file a request for `tail` under `path` should be answered with
+pub async fn resolve_file(path: &Path, tail: path::Tail) -> Result<PathBuf, Rejection> {
     let mut file_path = sanitize_path(path, tail.as_str())?;
     let is_dir = tokio::fs::metadata(file_path.clone())
         .await
@@ -32,11 +56,47 @@ pub async fn serve_file(path: &PathBuf, tail: path::Tail) -> Result<Response, Re
         file_path.push("index.html");
     }
 
-    file_reply(file_path).await
+    // open what we checked, so a symlink swapped in afterwards can't lead us anywhere else
+    ensure_contained(path, &file_path).await
 }
 
-fn sanitize_path(path: &PathBuf, tail: &str) -> Result<PathBuf, Rejection> {
-    let mut buf = path.clone();
+/// Make sure that following any symlinks on the way to our file keeps us under our root,
+/// returning the canonical path we checked
+async fn ensure_contained(root: &Path, file_path: &Path) -> Result<PathBuf, Rejection> {
+    let canonical_root = tokio::fs::canonicalize(root).await;
+    let canonical_file = match tokio::fs::canonicalize(file_path).await {
+        // a file that doesn't exist isn't anywhere, but its directory still has to be ours so
+        // that opening it reports that it's missing
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            match (file_path.parent(), file_path.file_name()) {
+                (Some(parent), Some(name)) => tokio::fs::canonicalize(parent)
+                    .await
+                    .map(|parent| parent.join(name)),
+                _ => Err(err),
+            }
+        }
+        canonical_file => canonical_file,
+    };
+
+    match (canonical_root, canonical_file) {
+        (Ok(root), Ok(file)) if file.starts_with(&root) => Ok(file),
+        (Ok(root), Ok(file)) => {
+            log::warn!(
+                "dir: rejecting {:?}, it resolves outside of {:?}",
+                file,
+                root
+            );
+            Err(reject::not_found())
+        }
+        (_, Err(err)) | (Err(err), _) => {
+            log::debug!("dir: couldn't resolve {:?}: {}", file_path, err);
+            Err(reject::not_found())
+        }
+    }
+}
+
+pub fn sanitize_path(path: &Path, tail: &str) -> Result<PathBuf, Rejection> {
+    let mut buf = path.to_path_buf();
     let p = match decode(tail) {
         Ok(p) => p,
         Err(err) => {
@@ -53,6 +113,16 @@ fn sanitize_path(path: &PathBuf, tail: &str) -> Result<PathBuf, Rejection> {
         } else if seg.contains('\\') {
             log::warn!("dir: rejecting segment containing with backslash (\\)");
             return Err(reject::not_found());
+        } else if seg.contains('\0') {
+            log::warn!("dir: rejecting segment containing a NUL byte");
+            return Err(reject::not_found());
+        } else if Path::new(seg)
+            .components()
+            .any(|c| matches!(c, Component::Prefix(_) | Component::RootDir))
+        {
+            // pushing an absolute segment would replace everything before it
+            log::warn!("dir: rejecting absolute segment");
+            return Err(reject::not_found());
         } else {
             buf.push(seg);
         }
@@ -60,11 +130,11 @@ fn sanitize_path(path: &PathBuf, tail: &str) -> Result<PathBuf, Rejection> {
     Ok(buf)
 }
 
-async fn file_reply(path: PathBuf) -> Result<Response, Rejection> {
+pub async fn file_reply(path: PathBuf, conditionals: &Conditionals) -> Result<Response, Rejection> {
     let file_result = TkFile::open(path.clone()).await;
 
     match file_result {
-        Ok(f) => file_conditional(f, path).await,
+        Ok(f) => file_conditional(f, path, conditionals).await,
         Err(err) => {
             let rej = match err.kind() {
                 io::ErrorKind::NotFound => {
@@ -95,47 +165,386 @@ async fn file_metadata(f: TkFile) -> Result<(TkFile, Metadata), Rejection> {
     }
 }
 
-async fn file_conditional(f: TkFile, path: PathBuf) -> Result<Response, Rejection> {
+async fn file_conditional(
+    f: TkFile,
+    path: PathBuf,
+    conditionals: &Conditionals,
+) -> Result<Response, Rejection> {
     let (file, meta) = file_metadata(f).await?;
 
     let len = meta.len();
-    let modified = meta.modified().ok().map(LastModified::from);
+    let modified = meta.modified().ok();
+    let etag = modified.map(|modified| file_etag(modified, len));
+    let mime = mime_guess::from_path(&path).first_or_octet_stream();
+
+    if let (Some(encoding), Some(etag)) = (conditionals.encoding(&mime, len), &etag) {
+        let key = path.to_string_lossy().into_owned();
+        let read_path = path.clone();
+        let compressed = dir_compression_cache()
+            .get_or_compress_blocking(key, etag.clone(), encoding, move || {
+                std::fs::read(read_path).map(Bytes::from)
+            })
+            .await;
+
+        match compressed {
+            Ok(compressed) => {
+                return Ok(encoded_reply(
+                    conditionals,
+                    compressed,
+                    encoding,
+                    mime,
+                    modified.map(LastModified::from),
+                    etag,
+                ))
+            }
+            Err(e) => log::warn!("Couldn't compress {:?} with {:?}: {}", path, encoding, e),
+        }
+    }
 
     let buf_size = optimal_buf_size(&meta);
-    let stream = file_stream(file, buf_size, len);
-    let body = Body::wrap_stream(stream);
+    // our first range reads from the file we opened, any others open it again
+    let mut file = Some(file);
+    let read = move |start, end| match file.take() {
+        Some(file) => file_stream(file, buf_size, (start, end)).boxed(),
+        None => stream::once(TkFile::open(path.clone()))
+            .map(move |result| match result {
+                Ok(file) => file_stream(file, buf_size, (start, end)).left_stream(),
+                Err(err) => stream::once(future::err(err)).right_stream(),
+            })
+            .flatten()
+            .boxed(),
+    };
 
-    let mut resp = Response::new(body);
+    Ok(conditional_reply(
+        conditionals,
+        len,
+        mime,
+        modified.map(LastModified::from),
+        etag,
+        read,
+    ))
+}
 
-    let mime = mime_guess::from_path(&path).first_or_octet_stream();
+/// The headers that change how we answer a request for a file
+#[derive(Debug, Default)]
+pub struct Conditionals {
+    if_none_match: Option<IfNoneMatch>,
+    if_modified_since: Option<IfModifiedSince>,
+    if_range: Option<IfRange>,
+    range: Option<Range>,
+    accept_encoding: Option<HeaderValue>,
+}
 
-    resp.headers_mut().typed_insert(ContentLength(len));
-    resp.headers_mut().typed_insert(ContentType::from(mime));
-    resp.headers_mut().typed_insert(AcceptRanges::bytes());
+/// The parts of a file a request asked for, as `(start, end)` with an exclusive end
+#[derive(Debug, PartialEq, Eq)]
+enum Ranges {
+    Full,
+    Partial(Vec<(u64, u64)>),
+    Unsatisfiable,
+}
+
+/// More ranges than this in one request get the whole file, so that a request can't make us
+/// read a file over and over
+const MAX_RANGES: usize = 32;
+
+impl Conditionals {
+    pub fn new(headers: &HeaderMap) -> Conditionals {
+        Conditionals {
+            if_none_match: headers.typed_get(),
+            if_modified_since: headers.typed_get(),
+            if_range: headers.typed_get(),
+            range: headers.typed_get(),
+            accept_encoding: headers.get(ACCEPT_ENCODING).cloned(),
+        }
+    }
+
+    /// The encoding to send our file in, if any. Ranges are always sent as they are, since
+    /// their offsets are into our file and not its encoding.
+    pub fn encoding(&self, mime: &Mime, len: u64) -> Option<Encoding> {
+        if self.range.is_some() || !is_compressible(mime, len) {
+            return None;
+        }
+
+        Encoding::negotiate(self.accept_encoding.as_ref()?.to_str().ok()?)
+    }
+
+    /// Whether the client's copy of our file is still current
+    fn not_modified(&self, etag: Option<&ETag>, last_modified: Option<&LastModified>) -> bool {
+        // If-Modified-Since only counts when there are no etags to compare
+        if let Some(if_none_match) = &self.if_none_match {
+            return etag.is_some_and(|etag| !if_none_match.precondition_passes(etag));
+        }
 
-    if let Some(last_modified) = modified {
+        match (&self.if_modified_since, last_modified) {
+            (Some(since), Some(last_modified)) => {
+                !since.is_modified(SystemTime::from(*last_modified))
+            }
+            _ => false,
+        }
+    }
+
+    fn ranges(
+        &self,
+        len: u64,
+        etag: Option<&ETag>,
+        last_modified: Option<&LastModified>,
+    ) -> Ranges {
+        let range = match &self.range {
+            Some(range) => range,
+            None => return Ranges::Full,
+        };
+
+        // a range of an older version of our file is useless, so they get all of it
+        if let Some(if_range) = &self.if_range {
+            if if_range.is_modified(etag, last_modified) {
+                log::trace!("if-range? {:?} vs {:?} failed", if_range, last_modified);
+                return Ranges::Full;
+            }
+        }
+
+        let specs: Vec<_> = range.iter().collect();
+        if specs.is_empty() || specs.len() > MAX_RANGES {
+            return Ranges::Full;
+        }
+
+        let satisfiable: Vec<_> = specs
+            .into_iter()
+            .filter_map(|(start, end)| match (start, end) {
+                (Bound::Included(start), _) if start >= len => None,
+                (Bound::Included(start), Bound::Included(end)) if start <= end => {
+                    Some((start, cmp::min(end + 1, len)))
+                }
+                (Bound::Included(start), Bound::Unbounded) => Some((start, len)),
+                // `-<n>` asks for our last n bytes
+                (Bound::Unbounded, Bound::Included(suffix)) if suffix > 0 && len > 0 => {
+                    Some((len.saturating_sub(suffix), len))
+                }
+                _ => None,
+            })
+            .collect();
+
+        if satisfiable.is_empty() {
+            log::trace!("unsatisfiable byte range: {:?} of {}", range, len);
+            Ranges::Unsatisfiable
+        } else {
+            Ranges::Partial(satisfiable)
+        }
+    }
+}
+
+/// Reply with the parts of a file our request asked for, reading each part with `read`, or with
+/// `304 Not Modified` if the client's copy is still current
+pub fn conditional_reply<F>(
+    conditionals: &Conditionals,
+    len: u64,
+    mime: Mime,
+    last_modified: Option<LastModified>,
+    etag: Option<ETag>,
+    mut read: F,
+) -> Response
+where
+    F: FnMut(u64, u64) -> BoxStream<'static, Result<Bytes, io::Error>>,
+{
+    // the same url could be sent compressed to someone else
+    let vary = is_compressible(&mime, len);
+
+    if conditionals.not_modified(etag.as_ref(), last_modified.as_ref()) {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = StatusCode::NOT_MODIFIED;
+        if vary {
+            resp.headers_mut()
+                .insert(VARY, HeaderValue::from_static("accept-encoding"));
+        }
+        if let Some(etag) = etag {
+            resp.headers_mut().typed_insert(etag);
+        }
+        if let Some(last_modified) = last_modified {
+            resp.headers_mut().typed_insert(last_modified);
+        }
+
+        return resp;
+    }
+
+    let mut resp = match conditionals.ranges(len, etag.as_ref(), last_modified.as_ref()) {
+        Ranges::Full => {
+            let mut resp = Response::new(Body::wrap_stream(read(0, len)));
+            resp.headers_mut().typed_insert(ContentLength(len));
+            resp.headers_mut().typed_insert(ContentType::from(mime));
+
+            resp
+        }
+        Ranges::Partial(ranges) if ranges.len() == 1 => {
+            let (start, end) = ranges[0];
+
+            let mut resp = Response::new(Body::wrap_stream(read(start, end)));
+            *resp.status_mut() = StatusCode::PARTIAL_CONTENT;
+            resp.headers_mut().typed_insert(
+                ContentRange::bytes(start..end, len).expect("Our range should be valid"),
+            );
+            resp.headers_mut().typed_insert(ContentLength(end - start));
+            resp.headers_mut().typed_insert(ContentType::from(mime));
+
+            resp
+        }
+        Ranges::Partial(ranges) => {
+            let boundary = multipart_boundary();
+
+            let mut body_len = 0;
+            let mut parts = Vec::with_capacity(ranges.len() * 2 + 1);
+            for (start, end) in ranges {
+                let part_headers = format!(
+                    "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    boundary,
+                    mime,
+                    start,
+                    end - 1,
+                    len
+                );
+                body_len += part_headers.len() as u64 + (end - start);
+                parts.push(stream::once(future::ok(Bytes::from(part_headers))).boxed());
+                parts.push(read(start, end));
+            }
+            let closing = format!("\r\n--{}--\r\n", boundary);
+            body_len += closing.len() as u64;
+            parts.push(stream::once(future::ok(Bytes::from(closing))).boxed());
+
+            let mut resp = Response::new(Body::wrap_stream(stream::iter(parts).flatten()));
+            *resp.status_mut() = StatusCode::PARTIAL_CONTENT;
+            resp.headers_mut().typed_insert(ContentLength(body_len));
+            resp.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_str(&format!("multipart/byteranges; boundary={}", boundary))
+                    .expect("Our boundary should be a valid header"),
+            );
+
+            resp
+        }
+        Ranges::Unsatisfiable => {
+            let mut resp = Response::new(Body::empty());
+            *resp.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+            resp.headers_mut()
+                .typed_insert(ContentRange::unsatisfied_bytes(len));
+
+            resp
+        }
+    };
+
+    resp.headers_mut().typed_insert(AcceptRanges::bytes());
+    if vary {
+        resp.headers_mut()
+            .insert(VARY, HeaderValue::from_static("accept-encoding"));
+    }
+    if let Some(etag) = etag {
+        resp.headers_mut().typed_insert(etag);
+    }
+    if let Some(last_modified) = last_modified {
         resp.headers_mut().typed_insert(last_modified);
     }
 
-    Ok(resp)
+    resp
+}
+
+/// Reply with our file compressed, still answering conditional requests against the etag of
+/// its encoding
+pub fn encoded_reply(
+    conditionals: &Conditionals,
+    compressed: Bytes,
+    encoding: Encoding,
+    mime: Mime,
+    last_modified: Option<LastModified>,
+    etag: &ETag,
+) -> Response {
+    let mut resp = conditional_reply(
+        conditionals,
+        compressed.len() as u64,
+        mime,
+        last_modified,
+        Some(encoding.etag(etag)),
+        |start, end| {
+            let part = compressed.slice(start as usize..end as usize);
+            stream::once(future::ok(part)).boxed()
+        },
+    );
+
+    // we never send ranges of our encodings
+    resp.headers_mut().remove(ACCEPT_RANGES);
+    resp.headers_mut()
+        .insert(VARY, HeaderValue::from_static("accept-encoding"));
+    if resp.status() != StatusCode::NOT_MODIFIED {
+        resp.headers_mut()
+            .insert(CONTENT_ENCODING, encoding.header_value());
+    }
+
+    resp
+}
+
+/// The compressed files from every book served from disk, by their full path
+pub fn dir_compression_cache() -> Arc<CompressionCache> {
+    static CACHE: OnceLock<Arc<CompressionCache>> = OnceLock::new();
+
+    CACHE.get_or_init(Default::default).clone()
+}
+
+/// A strong etag for a file on disk. Anything that rewrites a file changes its modification
+/// time, so together with its size that's enough to tell versions apart.
+fn file_etag(modified: SystemTime, len: u64) -> ETag {
+    let modified = modified
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_nanos())
+        .unwrap_or_default();
+
+    format!("\"{:x}-{:x}\"", modified, len)
+        .parse()
+        .expect("Our etag should always be valid")
+}
+
+/// A strong etag for a file we only have in memory
+pub fn content_etag(contents: &[u8]) -> ETag {
+    // the same contents keep the same etag across restarts and Rust releases
+    format!("\"{:016x}-{:x}\"", fnv1a(contents), contents.len())
+        .parse()
+        .expect("Our etag should always be valid")
+}
+
+/// A boundary for our `multipart/byteranges` replies that won't turn up in the parts themselves
+fn multipart_boundary() -> String {
+    static COUNT: AtomicU64 = AtomicU64::new(0);
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let count = COUNT.fetch_add(1, Ordering::Relaxed);
+    let seed = [now.to_le_bytes().as_ref(), &count.to_le_bytes()].concat();
+
+    format!("mdnotes-{:016x}", fnv1a(&seed))
 }
 
 fn file_stream(
-    file: TkFile,
+    mut file: TkFile,
     buf_size: usize,
-    mut len: u64,
+    (start, end): (u64, u64),
 ) -> impl Stream<Item = Result<Bytes, io::Error>> + Send {
-    let seek = async move { Ok(file) };
+    let seek = async move {
+        if start != 0 {
+            file.seek(SeekFrom::Start(start)).await?;
+        }
+        Ok(file)
+    };
 
     seek.into_stream()
         .map(move |result| {
             let mut buf = BytesMut::new();
+            let mut len = end - start;
             let mut f = match result {
                 Ok(f) => f,
                 Err(f) => return Either::Left(stream::once(future::err(f))),
             };
 
             Either::Right(stream::poll_fn(move |cx| {
+                if len == 0 {
+                    return Poll::Ready(None);
+                }
                 reserve_at_least(&mut buf, buf_size);
 
                 let n = match ready!(Pin::new(&mut f).poll_read_buf(cx, &mut buf)) {
@@ -0,0 +1,400 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use std::{fs, mem};
+
+use mdbook::book::BookItem;
+use mdbook::MDBook;
+use notify::DebouncedEvent;
+use regex::Regex;
+use toml::Value;
+
+use crate::poll_watcher::{PollWatcher, Snapshot};
+use crate::watcher::{NotesWatcher, WatcherFactory};
+use crate::WatchMode;
+
+/// How long changes can go without a native event before we decide our native watcher is blind
+const NATIVE_GRACE: Duration = Duration::from_secs(1);
+/// The longest we wait between checks on our native watcher. Each check walks our whole book, so
+/// books that rarely change are checked less and less often.
+const CANARY_MAX_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How deep we follow includes inside included files, the same limit mdbook uses
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+/// Everything on disk that affects how our book builds
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WatchSet {
+    /// Directories whose whole contents affect our build, like our source and theme
+    pub dirs: BTreeSet<PathBuf>,
+    /// Single files that affect our build, like `book.toml`, additional css and js, preprocessor
+    /// scripts and anything pulled in with `{{#include}}`
+    pub files: BTreeSet<PathBuf>,
+}
+
+impl WatchSet {
+    /// The watch mode our book asked for in its `[mdnotes]` table, if any
+    pub fn watch_mode(book: &MDBook) -> Option<WatchMode> {
+        let name = book.config.get("mdnotes.watch")?.as_str();
+        let mode = name.and_then(WatchMode::from_name);
+        if mode.is_none() {
+            warn!(
+                "Ignoring mdnotes.watch = {:?} in {:?}, it should be \"auto\", \"native\" or \"polling\"",
+                name, book.root
+            );
+        }
+
+        mode
+    }
+
+    pub fn for_book(book: &MDBook) -> WatchSet {
+        let root = &book.root;
+        let mut set = WatchSet::default();
+
+        set.dirs.insert(normalize(&book.source_dir()));
+        set.dirs.insert(normalize(&book.theme_dir()));
+        set.files.insert(root.join("book.toml"));
+
+        if let Some(html_config) = book.config.html_config() {
+            for extra in html_config
+                .additional_css
+                .iter()
+                .chain(&html_config.additional_js)
+            {
+                set.files.insert(normalize(&root.join(extra)));
+            }
+        }
+
+        // scripts in the book that our preprocessors and renderers run
+        for table in &["preprocessor", "output"] {
+            let commands = book
+                .config
+                .get(table)
+                .and_then(Value::as_table)
+                .into_iter()
+                .flat_map(|table| table.values())
+                .filter_map(|entry| entry.get("command").and_then(Value::as_str));
+
+            for command in commands {
+                set.files.extend(
+                    command
+                        .split_whitespace()
+                        .map(|part| normalize(&root.join(part)))
+                        .filter(|path| path.starts_with(root) && path.is_file()),
+                );
+            }
+        }
+
+        let source_dir = book.source_dir();
+        for item in book.iter() {
+            if let BookItem::Chapter(chapter) = item {
+                let chapter_dir = source_dir
+                    .join(&chapter.path)
+                    .parent()
+                    .map(Path::to_path_buf)
+                    .unwrap_or_else(|| source_dir.clone());
+
+                add_includes(&mut set.files, &chapter.content, &chapter_dir, 0);
+            }
+        }
+
+        set
+    }
+
+    /// Add anything this chapter includes, after it was changed and re-rendered on its own
+    pub fn add_includes_of(&mut self, chapter: &Path) {
+        if let Ok(content) = fs::read_to_string(chapter) {
+            let chapter_dir = chapter.parent().unwrap_or(chapter);
+            add_includes(&mut self.files, &content, chapter_dir, 0);
+        }
+    }
+
+    /// Whether a change to this path affects our build
+    pub fn contains(&self, path: &Path) -> bool {
+        self.files.contains(path) || self.dirs.iter().any(|dir| path.starts_with(dir))
+    }
+
+    /// What we need to watch, and whether it's recursive. Files are watched through their
+    /// directory since editors often save by replacing the file, which loses a direct watch.
+    fn watches(&self) -> BTreeMap<PathBuf, bool> {
+        let mut watches: BTreeMap<_, _> = self.dirs.iter().map(|dir| (dir.clone(), true)).collect();
+
+        for file in &self.files {
+            if let Some(parent) = file.parent() {
+                if !self.dirs.iter().any(|dir| parent.starts_with(dir)) {
+                    watches.entry(parent.to_path_buf()).or_insert(false);
+                }
+            }
+        }
+
+        watches
+    }
+}
+
+/// Find the files pulled in by mdbook's `{{#include}}`, `{{#rustdoc_include}}` and
+/// `{{#playpen}}` links, along with anything they include in turn
+fn add_includes(files: &mut BTreeSet<PathBuf>, content: &str, dir: &Path, depth: usize) {
+    static LINK_REGEX: OnceLock<Regex> = OnceLock::new();
+    let link_regex = LINK_REGEX.get_or_init(|| {
+        // the same links mdbook's links preprocessor looks for
+        Regex::new(
+            r"\\\{\{\#.*\}\}|\{\{\s*\#(include|rustdoc_include|playpen)\s+([a-zA-Z0-9\s_.\-:/\\]+)\}\}",
+        )
+        .unwrap()
+    });
+
+    if depth >= MAX_INCLUDE_DEPTH {
+        return;
+    }
+
+    for captures in link_regex.captures_iter(content) {
+        let (kind, target) = match (captures.get(1), captures.get(2)) {
+            (Some(kind), Some(target)) => (kind.as_str(), target.as_str()),
+            // an escaped link
+            _ => continue,
+        };
+
+        let target = target.split_whitespace().next().unwrap_or_default();
+        let target = match kind {
+            // drop any anchor or line range
+            "include" | "rustdoc_include" => target.split(':').next().unwrap_or_default(),
+            _ => target,
+        };
+        if target.is_empty() {
+            continue;
+        }
+
+        let path = normalize(&dir.join(target));
+        if files.insert(path.clone()) {
+            if let Ok(included) = fs::read_to_string(&path) {
+                let included_dir = path.parent().unwrap_or(dir);
+                add_includes(files, &included, included_dir, depth + 1);
+            }
+        }
+    }
+}
+
+/// Resolve any `.` and `..` in our path without touching the disk, so that it matches the paths
+/// our watcher reports
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+
+    normalized
+}
+
+/// Checks that our native watcher actually sees changes, for filesystems like network shares
+/// that accept watches but never send events
+struct Canary {
+    snapshot: Snapshot,
+    last_check: Instant,
+    /// How long until our next check, doubling every time nothing has changed
+    interval: Duration,
+    /// When we first saw a change that our native watcher didn't tell us about
+    suspicious: Option<Instant>,
+}
+
+/// Our filesystem watches, kept in line with our book's watch set
+pub struct Watches {
+    /// Whatever is sending us filesystem events
+    backend: Box<dyn NotesWatcher>,
+    sender: Sender<DebouncedEvent>,
+    poll_interval: Duration,
+    /// Only set while we're in auto mode and haven't seen a native event yet
+    canary: Option<Canary>,
+    /// What we're watching and whether it's recursive
+    watched: BTreeMap<PathBuf, bool>,
+    set: WatchSet,
+}
+
+impl Watches {
+    pub fn new(
+        native: &dyn WatcherFactory,
+        mode: WatchMode,
+        poll_interval: Duration,
+        sender: Sender<DebouncedEvent>,
+    ) -> Result<Watches, notify::Error> {
+        let polling = || -> Box<dyn NotesWatcher> {
+            Box::new(PollWatcher::new(sender.clone(), poll_interval))
+        };
+        let (backend, canary) = match mode {
+            WatchMode::Native => (native.start(sender.clone())?, None),
+            WatchMode::Auto => match native.start(sender.clone()) {
+                Ok(watcher) => {
+                    let canary = Canary {
+                        snapshot: Snapshot::take(&BTreeMap::new()),
+                        last_check: Instant::now(),
+                        interval: poll_interval,
+                        suspicious: None,
+                    };
+
+                    (watcher, Some(canary))
+                }
+                Err(e) => {
+                    info!(
+                        "Polling for changes, we couldn't start a native watcher: {}",
+                        e
+                    );
+                    (polling(), None)
+                }
+            },
+            WatchMode::Polling => (polling(), None),
+        };
+
+        Ok(Watches {
+            backend,
+            sender,
+            poll_interval,
+            canary,
+            watched: BTreeMap::new(),
+            set: WatchSet::default(),
+        })
+    }
+
+    pub fn set(&self) -> &WatchSet {
+        &self.set
+    }
+
+    /// Let our watcher know we've handled everything it sent us, see
+    /// `NotesWatcher::between_batches`
+    pub fn between_batches(&mut self, nothing_waiting: &mut dyn FnMut() -> bool) {
+        self.backend.between_batches(nothing_waiting);
+    }
+
+    /// Our native watcher sent us something, so it works and we can stop checking on it
+    pub fn native_event(&mut self) {
+        if self.canary.take().is_some() {
+            debug!("Our native watcher works, we won't check on it anymore");
+        }
+    }
+
+    /// Compare our watches against the filesystem, backing off from every poll interval while
+    /// nothing changes, and switch to polling if something changed that our native watcher never
+    /// told us about. Returns the paths it missed.
+    pub fn check_canary(&mut self) -> Vec<PathBuf> {
+        let canary = match &mut self.canary {
+            Some(canary) if canary.last_check.elapsed() >= canary.interval => canary,
+            _ => return vec![],
+        };
+        canary.last_check = Instant::now();
+
+        let missed = match canary.suspicious {
+            Some(since) if since.elapsed() >= NATIVE_GRACE => {
+                let snapshot = Snapshot::take(&self.watched);
+                let missed = canary.snapshot.changes(&snapshot);
+
+                info!(
+                    "Polling for changes, our native watcher missed {} change(s)",
+                    missed.len()
+                );
+                missed
+            }
+            Some(_) => return vec![],
+            None => {
+                let snapshot = Snapshot::take(&self.watched);
+                if canary.snapshot.changes(&snapshot).is_empty() {
+                    canary.snapshot = snapshot;
+                    canary.interval = (canary.interval * 2).min(CANARY_MAX_INTERVAL);
+                } else {
+                    // give our native watcher a chance to catch up, then look again soon
+                    canary.suspicious = Some(Instant::now());
+                    canary.interval = self.poll_interval;
+                }
+                return vec![];
+            }
+        };
+
+        self.switch_to_polling();
+
+        missed
+            .into_iter()
+            .filter_map(|event| match event {
+                DebouncedEvent::Create(path)
+                | DebouncedEvent::Write(path)
+                | DebouncedEvent::Remove(path) => Some(path),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn switch_to_polling(&mut self) {
+        self.canary = None;
+        self.backend = Box::new(PollWatcher::new(self.sender.clone(), self.poll_interval));
+
+        let watched = mem::take(&mut self.watched);
+        for (path, recursive) in watched {
+            match self.backend.watch(&path, recursive) {
+                Ok(()) => {
+                    self.watched.insert(path, recursive);
+                }
+                Err(e) => warn!("Couldn't poll book directory '{:?}': {}", path, e),
+            }
+        }
+    }
+
+    /// Watch everything in our new set and drop the watches we no longer need, returning
+    /// whether we're watching anything
+    pub fn update(&mut self, set: WatchSet) -> bool {
+        if set == self.set && !self.watched.is_empty() {
+            return true;
+        }
+
+        let wanted = set.watches();
+
+        for (path, recursive) in &self.watched {
+            if wanted.get(path) != Some(recursive) {
+                debug!("No longer watching {:?}", path);
+                if let Err(e) = self.backend.unwatch(path) {
+                    // it's probably been removed
+                    debug!("Couldn't stop watching {:?}: {}", path, e);
+                }
+            }
+        }
+
+        let mut watched = BTreeMap::new();
+        let mut failed = false;
+        for (path, recursive) in wanted {
+            if self.watched.get(&path) == Some(&recursive) {
+                watched.insert(path, recursive);
+                continue;
+            }
+
+            match self.backend.watch(&path, recursive) {
+                Ok(()) => {
+                    debug!("Watching {:?}", path);
+                    // anything that changes from here on should have sent us an event
+                    if let Some(canary) = &mut self.canary {
+                        canary.snapshot.watch(&path, recursive);
+                    }
+                    watched.insert(path, recursive);
+                }
+                Err(e) => {
+                    warn!("Couldn't watch book directory '{:?}': {}", path, e);
+                    failed = true;
+                }
+            }
+        }
+
+        self.watched = watched;
+        self.set = set;
+
+        // our native watcher can't handle this filesystem at all
+        if failed && self.watched.is_empty() && self.canary.is_some() {
+            info!("Polling for changes, our native watcher couldn't watch anything");
+            self.watched = self.set.watches();
+            self.switch_to_polling();
+        }
+
+        !self.watched.is_empty()
+    }
+}
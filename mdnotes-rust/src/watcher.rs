@@ -0,0 +1,55 @@
+use std::fmt;
+use std::path::Path;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+
+/// Something that sends our notes filesystem events for the paths they ask it to watch
+pub trait NotesWatcher: Send {
+    fn watch(&mut self, path: &Path, recursive: bool) -> Result<(), notify::Error>;
+
+    fn unwatch(&mut self, path: &Path) -> Result<(), notify::Error>;
+
+    /// Called by our notes between batches of events, with a check that takes any waiting event
+    /// and returns whether there was none. Our fake uses it to tell tests their events were
+    /// handled.
+    fn between_batches(&mut self, _nothing_waiting: &mut dyn FnMut() -> bool) {}
+}
+
+/// Starts the watchers our notes get their native file events from, one for each notebook
+pub trait WatcherFactory: fmt::Debug + Send + Sync {
+    fn start(&self, sender: Sender<DebouncedEvent>)
+        -> Result<Box<dyn NotesWatcher>, notify::Error>;
+}
+
+/// The platform's own file events through notify
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NativeWatchers;
+
+impl WatcherFactory for NativeWatchers {
+    fn start(
+        &self,
+        sender: Sender<DebouncedEvent>,
+    ) -> Result<Box<dyn NotesWatcher>, notify::Error> {
+        let watcher: RecommendedWatcher = Watcher::new(sender, Duration::from_millis(100))?;
+
+        Ok(Box::new(watcher))
+    }
+}
+
+impl NotesWatcher for RecommendedWatcher {
+    fn watch(&mut self, path: &Path, recursive: bool) -> Result<(), notify::Error> {
+        let mode = if recursive {
+            RecursiveMode::Recursive
+        } else {
+            RecursiveMode::NonRecursive
+        };
+
+        Watcher::watch(self, path, mode)
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Result<(), notify::Error> {
+        Watcher::unwatch(self, path)
+    }
+}
@@ -0,0 +1,277 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mdbook::MDBook;
+use serde::{Deserialize, Serialize};
+
+use crate::incremental;
+use crate::output::NotesOutput;
+use crate::MdNotesError;
+
+/// The argument our worker executable is started with
+pub const BUILD_WORKER_COMMAND: &str = "build-worker";
+
+/// Marks the line our worker answers with, since preprocessors can write to its stdout too
+const RESPONSE_PREFIX: &str = "mdnotes-build-response: ";
+
+/// How often a worker's build checks whether it's been cancelled
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A single build of our book, run either in our own process or sent to a worker on its stdin
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BuildRequest {
+    pub book_dir: PathBuf,
+    pub livereload_url: String,
+    /// Where to build instead of the book's own build directory
+    pub build_dir: Option<PathBuf>,
+    /// Only re-render the chapters in these changed paths, if that's all that changed
+    pub changed_paths: Option<Vec<PathBuf>>,
+}
+
+/// How a build updated our output
+pub enum Built {
+    /// Only the changed chapters were re-rendered, straight into our output
+    InPlace,
+    /// The whole book was built into its build directory
+    Full(Box<MDBook>),
+}
+
+/// What our worker answers with on its stdout
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "kebab-case")]
+enum BuildResponse {
+    Built {
+        in_place: bool,
+    },
+    Failed {
+        message: String,
+        causes: Vec<String>,
+    },
+}
+
+impl BuildRequest {
+    pub fn load(&self) -> Result<MDBook, MdNotesError> {
+        let mut book = MDBook::load(&self.book_dir).map_err(MdNotesError::Config)?;
+
+        book.config
+            .set("output.html.livereload-url", &self.livereload_url)
+            .map_err(MdNotesError::Config)?;
+
+        if let Some(build_dir) = &self.build_dir {
+            book.config.build.build_dir = build_dir.clone();
+        }
+
+        Ok(book)
+    }
+
+    /// Run this build here, re-rendering changed chapters into `output` or else straight into
+    /// our html build directory
+    pub fn run(&self, output: Option<&NotesOutput>) -> Result<Built, MdNotesError> {
+        let book = self.load()?;
+
+        if let Some(changed_paths) = &self.changed_paths {
+            if let Some(chapters) = incremental::changed_chapters(&book, changed_paths) {
+                let build_dir_output;
+                let output = match output {
+                    Some(output) => output,
+                    None => {
+                        build_dir_output = NotesOutput::Dir(book.build_dir_for("html"));
+                        &build_dir_output
+                    }
+                };
+
+                match incremental::rebuild_chapters(&book, &chapters, output) {
+                    Ok(()) => return Ok(Built::InPlace),
+                    Err(e) => debug!(
+                        "Couldn't re-render {:?}, falling back on a full build: {}",
+                        chapters,
+                        MdNotesError::Build(e)
+                    ),
+                }
+            }
+        }
+
+        book.build().map_err(MdNotesError::Build)?;
+
+        Ok(Built::Full(Box::new(book)))
+    }
+
+    /// Run this build in a `<worker> build-worker` child process, killing it if it takes longer
+    /// than `timeout` or as soon as it's `cancelled`. Anything re-rendered in place goes straight
+    /// into our html build directory.
+    pub fn run_isolated<F>(
+        &self,
+        worker: &Path,
+        timeout: Duration,
+        cancelled: F,
+    ) -> Result<Built, MdNotesError>
+    where
+        F: Fn() -> bool,
+    {
+        let mut command = Command::new(worker);
+        command
+            .arg(BUILD_WORKER_COMMAND)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+
+        // give our worker its own process group, so that we can kill its preprocessors with it
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            command.process_group(0);
+        }
+
+        let mut child = command.spawn().map_err(|e| {
+            worker_error(format!(
+                "Couldn't start our build worker {:?}: {}",
+                worker, e
+            ))
+        })?;
+
+        let request = serde_json::to_string(self).expect("Our requests should always serialize");
+        if let Some(mut stdin) = child.stdin.take() {
+            // a worker that died straight away is reported when we wait on it
+            if let Err(e) = writeln!(stdin, "{}", request) {
+                debug!("Couldn't send our build request to our worker: {}", e);
+            }
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        if let Some(stdout) = child.stdout.take() {
+            thread::spawn(move || {
+                let response = BufReader::new(stdout)
+                    .lines()
+                    .map_while(Result::ok)
+                    .find_map(|line| {
+                        line.strip_prefix(RESPONSE_PREFIX)
+                            .and_then(|response| serde_json::from_str(response).ok())
+                    });
+
+                let _ = sender.send(response);
+            });
+        }
+
+        let started = Instant::now();
+        let response = loop {
+            if cancelled() {
+                kill(&mut child);
+
+                return Err(worker_error(
+                    "Our notes were closed, so their build was stopped".to_string(),
+                ));
+            }
+
+            let remaining = match timeout.checked_sub(started.elapsed()) {
+                Some(remaining) => remaining,
+                None => {
+                    kill(&mut child);
+
+                    return Err(worker_error(format!(
+                        "The build didn't finish within {:.1}s, so it was stopped",
+                        timeout.as_secs_f64()
+                    )));
+                }
+            };
+
+            // nothing tells us when we're cancelled, so check every so often
+            match receiver.recv_timeout(remaining.min(CANCEL_CHECK_INTERVAL)) {
+                Ok(response) => break response,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break None,
+            }
+        };
+
+        let status = child.wait()?;
+
+        match response {
+            Some(BuildResponse::Built { in_place: true }) => Ok(Built::InPlace),
+            Some(BuildResponse::Built { in_place: false }) => {
+                Ok(Built::Full(Box::new(self.load()?)))
+            }
+            Some(BuildResponse::Failed { message, causes }) => {
+                Err(MdNotesError::Worker { message, causes })
+            }
+            None => Err(worker_error(format!(
+                "Our build worker stopped without finishing the build ({})",
+                describe_status(status)
+            ))),
+        }
+    }
+}
+
+fn worker_error(message: String) -> MdNotesError {
+    MdNotesError::Worker {
+        causes: vec![message.clone()],
+        message,
+    }
+}
+
+/// Kill our worker along with anything it started
+fn kill(child: &mut Child) {
+    #[cfg(unix)]
+    {
+        // a negative pid signals the whole process group
+        unsafe {
+            libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+        }
+    }
+
+    if let Err(e) = child.kill() {
+        debug!("Couldn't kill our build worker: {}", e);
+    }
+    let _ = child.wait();
+}
+
+fn describe_status(status: ExitStatus) -> String {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return format!("killed by signal {}", signal);
+        }
+    }
+
+    status.to_string()
+}
+
+/// Run the single build request on our stdin and answer on our stdout. This is the whole of
+/// `mdnotes build-worker`, any executable passed as our worker needs to call it for that command.
+pub fn run_build_worker() -> i32 {
+    let _ = env_logger::try_init();
+
+    let result = read_request().and_then(|request| request.run(None));
+    let (response, code) = match result {
+        Ok(built) => (
+            BuildResponse::Built {
+                in_place: matches!(built, Built::InPlace),
+            },
+            0,
+        ),
+        Err(e) => (
+            BuildResponse::Failed {
+                message: e.to_string(),
+                causes: e.causes(),
+            },
+            1,
+        ),
+    };
+
+    let response = serde_json::to_string(&response).expect("Our responses should always serialize");
+    println!("{}{}", RESPONSE_PREFIX, response);
+    let _ = io::stdout().flush();
+
+    code
+}
+
+fn read_request() -> Result<BuildRequest, MdNotesError> {
+    let mut request = String::new();
+    io::stdin().read_line(&mut request)?;
+
+    serde_json::from_str(&request)
+        .map_err(|e| worker_error(format!("Couldn't read our build request: {}", e)))
+}
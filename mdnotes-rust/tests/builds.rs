@@ -0,0 +1,216 @@
+use std::fs;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use mdnotes::{
+    BuildOutput, EnvironmentSetup, FakeWatcher, MdNotesRuntime, NotesEvent, PortPolicy, WatchMode,
+};
+
+use crate::common::{wait_for, write_book, write_slow_book, ws_connect, ws_next_text, GatedBook};
+
+mod common;
+
+fn runtime(max_concurrent_builds: usize) -> MdNotesRuntime {
+    MdNotesRuntime::builder()
+        .port(PortPolicy::Random)
+        .environment(EnvironmentSetup::Skip)
+        .build_output(BuildOutput::Memory)
+        .max_concurrent_builds(max_concurrent_builds)
+        .build()
+        .unwrap()
+}
+
+/// Only builds for the changes we inject, once they've been handled
+fn watched_runtime(max_concurrent_builds: usize, watcher: &FakeWatcher) -> MdNotesRuntime {
+    MdNotesRuntime::builder()
+        .port(PortPolicy::Random)
+        .environment(EnvironmentSetup::Skip)
+        .build_output(BuildOutput::Memory)
+        .max_concurrent_builds(max_concurrent_builds)
+        .watcher(watcher.clone())
+        .watch_mode(WatchMode::Native)
+        .build()
+        .unwrap()
+}
+
+/// Tracks how many builds are running, and the most that ever ran at once
+#[derive(Default)]
+struct BuildCounter {
+    running: usize,
+    most_running: usize,
+    started: usize,
+    finished: usize,
+}
+
+fn count_builds(runtime: &MdNotesRuntime) -> Arc<Mutex<BuildCounter>> {
+    let counter = Arc::new(Mutex::new(BuildCounter::default()));
+    let listener_counter = counter.clone();
+    runtime.set_event_listener(move |_, event| {
+        let mut counter = listener_counter.lock().unwrap();
+        match event {
+            NotesEvent::Loading => {
+                counter.running += 1;
+                counter.started += 1;
+                counter.most_running = counter.most_running.max(counter.running);
+            }
+            NotesEvent::Built(_) | NotesEvent::BuildFailed(_) => {
+                counter.running -= 1;
+                counter.finished += 1;
+            }
+            NotesEvent::WatchFailed(_) | NotesEvent::Orphaned | NotesEvent::Closed => (),
+        }
+    });
+
+    counter
+}
+
+/// Whatever our websocket says next, for checking exactly which builds ran
+fn next_json(websocket: &mut tungstenite::WebSocket<std::net::TcpStream>) -> Value {
+    serde_json::from_str(&ws_next_text(websocket).unwrap()).unwrap()
+}
+
+#[test]
+fn changes_during_a_build_are_coalesced() {
+    let book = GatedBook::new("Coalesced");
+    let watcher = FakeWatcher::default();
+    let runtime = watched_runtime(2, &watcher);
+    let port = runtime.server_port();
+    runtime.open_notes(book.path().into()).unwrap();
+    let counter = count_builds(&runtime);
+    let chapter = book.path().join("src/chapter.md");
+
+    let mut websocket = ws_connect(port, "/coalesced/ws?protocol=json");
+
+    book.hold();
+    watcher.write(&chapter);
+    book.wait_for_runs(2);
+
+    // these all land while our first build is still running
+    for _ in 0..4 {
+        watcher.write(&chapter);
+    }
+    book.release();
+
+    // one more build for everything that changed while we were busy, and nothing after it
+    watcher.write(book.path().join("src/other.md"));
+    let mut changes = vec![];
+    while changes.len() < 3 {
+        let message = next_json(&mut websocket);
+        if message["type"] == "reload" {
+            changes.push(message["changes"][0]["path"].clone());
+        }
+    }
+    assert_eq!(
+        changes,
+        vec![
+            json!("src/chapter.md"),
+            json!("src/chapter.md"),
+            json!("src/other.md")
+        ]
+    );
+
+    let counter = counter.lock().unwrap();
+    assert_eq!(counter.started, 3);
+    assert_eq!(counter.finished, 3);
+}
+
+#[test]
+fn builds_are_limited_across_notes() {
+    let books: Vec<_> = (0..3)
+        .map(|i| write_slow_book(&format!("Limited {}", i), 0.3))
+        .collect();
+    let runtime = runtime(1);
+    for book in &books {
+        runtime.open_notes(book.path().into()).unwrap();
+    }
+    let counter = count_builds(&runtime);
+
+    for book in &books {
+        fs::write(book.path().join("src/chapter.md"), "# Changed\n").unwrap();
+    }
+
+    wait_for(|| counter.lock().unwrap().finished >= 3);
+
+    let counter = counter.lock().unwrap();
+    assert_eq!(counter.started, 3);
+    assert_eq!(counter.most_running, 1);
+}
+
+#[test]
+fn closing_notes_drops_their_queued_builds() {
+    let blocking = GatedBook::new("Blocking");
+    let queued = GatedBook::new("Queued");
+    let watcher = FakeWatcher::default();
+    let runtime = watched_runtime(1, &watcher);
+    runtime.open_notes(blocking.path().into()).unwrap();
+    let queued_id = runtime.open_notes(queued.path().into()).unwrap();
+
+    blocking.hold();
+    watcher.write(blocking.path().join("src/chapter.md"));
+    blocking.wait_for_runs(2);
+
+    // this build has to wait for our blocking build, so it's gone by the time it could run
+    watcher.write(queued.path().join("src/chapter.md"));
+    runtime.close_notes(queued_id).unwrap();
+    blocking.release();
+
+    // our limit of one build means the queued build would have run before this one
+    watcher.write(blocking.path().join("src/chapter.md"));
+    blocking.wait_for_runs(3);
+
+    assert_eq!(queued.runs(), 1);
+}
+
+#[test]
+fn slow_books_dont_hold_up_opening_others() {
+    let slow = GatedBook::new("Slow Open");
+    let quick = write_book("[book]\ntitle = \"Quick Open\"\n");
+    let runtime = Arc::new(runtime(2));
+
+    slow.hold();
+    let slow_runtime = runtime.clone();
+    let slow_dir = slow.path().to_path_buf();
+    let opening = thread::spawn(move || slow_runtime.open_notes(slow_dir).unwrap());
+    slow.wait_for_runs(1);
+
+    // our slow book is still building, so this would never return if it held us up
+    let (opened, quick_opened) = mpsc::channel();
+    let quick_runtime = runtime.clone();
+    let quick_dir = quick.path().to_path_buf();
+    thread::spawn(move || opened.send(quick_runtime.open_notes(quick_dir).unwrap()));
+    quick_opened
+        .recv_timeout(Duration::from_secs(30))
+        .expect("Opening a quick book should never wait for a slow one");
+
+    slow.release();
+    opening.join().unwrap();
+}
+
+#[test]
+fn opening_a_book_twice_at_once_shares_its_notes() {
+    let book = write_slow_book("Opened Twice", 0.5);
+    let runtime = Arc::new(runtime(2));
+
+    let openers: Vec<_> = (0..2)
+        .map(|_| {
+            let runtime = runtime.clone();
+            let book_dir = book.path().to_path_buf();
+            thread::spawn(move || runtime.open_notes(book_dir).unwrap())
+        })
+        .collect();
+    let ids: Vec<_> = openers
+        .into_iter()
+        .map(|opener| opener.join().unwrap())
+        .collect();
+
+    assert_eq!(ids[0], ids[1]);
+
+    // our notes stay open until both opens are closed
+    runtime.close_notes(ids[0]).unwrap();
+    assert!(runtime.notes_slug(ids[0]).is_some());
+    runtime.close_notes(ids[1]).unwrap();
+    assert!(runtime.notes_slug(ids[0]).is_none());
+}
@@ -0,0 +1,340 @@
+use std::ffi::{CStr, CString};
+use std::os::raw::c_void;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use mdnotes::*;
+
+use crate::common::write_book;
+
+mod common;
+
+fn last_error() -> (MdNotesStatus, String) {
+    let message = md_notes_last_error_message();
+    assert!(!message.is_null(), "We should have an error message");
+
+    let message = unsafe { CStr::from_ptr(message) }
+        .to_string_lossy()
+        .into_owned();
+
+    (md_notes_last_error_status(), message)
+}
+
+/// Keep our tests from saving a server port or importing a login shell environment
+unsafe fn test_runtime() -> *mut MdNotesRuntime {
+    let mut options = md_notes_runtime_options_default();
+    options.port_policy = MdNotesPortPolicy::Random;
+    options.skip_environment = true;
+    options.build_output = MdNotesBuildOutput::Memory;
+
+    let runtime = md_notes_runtime_new_with_options(&options);
+    assert!(!runtime.is_null(), "{:?}", last_error());
+
+    runtime
+}
+
+fn c_path(path: &Path) -> CString {
+    CString::new(path.to_str().unwrap()).unwrap()
+}
+
+#[test]
+fn null_runtime_is_rejected() {
+    unsafe {
+        md_notes_clear_last_error();
+
+        assert_eq!(md_notes_runtime_server_port(ptr::null_mut()), 0);
+        assert_eq!(last_error().0, MdNotesStatus::NullPointer);
+
+        let path = CString::new("/").unwrap();
+        assert_eq!(
+            md_notes_runtime_open_notes(ptr::null_mut(), path.as_ptr()),
+            0
+        );
+        assert_eq!(last_error().0, MdNotesStatus::NullPointer);
+
+        assert_eq!(
+            md_notes_runtime_close_notes(ptr::null_mut(), 1),
+            MdNotesStatus::NullPointer
+        );
+
+        // freeing null is a no-op
+        md_notes_runtime_free(ptr::null_mut());
+    }
+}
+
+#[test]
+fn stress_bad_calls_never_abort() {
+    let invalid_book = write_book("[book\ntitle = ");
+    let missing_summary = tempfile::tempdir().unwrap();
+    let valid_book = write_book("[book]\ntitle = \"Valid\"\n");
+
+    unsafe {
+        let runtime = test_runtime();
+        assert!(!runtime.is_null());
+        assert_ne!(md_notes_runtime_server_port(runtime), 0);
+
+        for _ in 0..20 {
+            md_notes_clear_last_error();
+
+            assert_eq!(md_notes_runtime_open_notes(runtime, ptr::null()), 0);
+            assert_eq!(last_error().0, MdNotesStatus::NullPointer);
+
+            let invalid_utf8 = CString::new(vec![0xff, 0xfe]).unwrap();
+            assert_eq!(
+                md_notes_runtime_open_notes(runtime, invalid_utf8.as_ptr()),
+                0
+            );
+            assert_eq!(last_error().0, MdNotesStatus::InvalidArgument);
+
+            for book in &[&invalid_book, &missing_summary] {
+                let path = c_path(book.path());
+                assert_eq!(md_notes_runtime_open_notes(runtime, path.as_ptr()), 0);
+                assert_eq!(last_error().0, MdNotesStatus::Config);
+            }
+
+            assert_eq!(
+                md_notes_runtime_close_notes(runtime, 0),
+                MdNotesStatus::InvalidNotesId
+            );
+            assert_eq!(
+                md_notes_runtime_close_notes(runtime, 200),
+                MdNotesStatus::InvalidNotesId
+            );
+        }
+
+        // after all of that our runtime should still be usable
+        let path = c_path(valid_book.path());
+        let notes_id = md_notes_runtime_open_notes(runtime, path.as_ptr());
+        assert_ne!(notes_id, 0, "{:?}", last_error());
+        assert_eq!(
+            md_notes_runtime_close_notes(runtime, notes_id),
+            MdNotesStatus::Ok
+        );
+        assert_eq!(
+            md_notes_runtime_close_notes(runtime, notes_id),
+            MdNotesStatus::InvalidNotesId
+        );
+
+        md_notes_runtime_free(runtime);
+    }
+}
+
+extern "C" fn record_event(context: *mut c_void, event: *const MdNotesEvent) {
+    let events = unsafe { &*(context as *const Mutex<Vec<(MdNotesEventKind, NotesId, bool)>>) };
+    let event = unsafe { &*event };
+
+    events
+        .lock()
+        .unwrap()
+        .push((event.kind, event.notes_id, !event.message.is_null()));
+}
+
+#[test]
+fn event_callback_receives_lifecycle() {
+    let invalid_book = write_book("[book\ntitle = ");
+    let valid_book = write_book("[book]\ntitle = \"Valid\"\n");
+    let events: Mutex<Vec<(MdNotesEventKind, NotesId, bool)>> = Mutex::new(vec![]);
+
+    unsafe {
+        let runtime = test_runtime();
+        assert_eq!(
+            md_notes_runtime_set_event_callback(
+                runtime,
+                Some(record_event),
+                &events as *const _ as *mut c_void,
+            ),
+            MdNotesStatus::Ok
+        );
+
+        let path = c_path(valid_book.path());
+        let notes_id = md_notes_runtime_open_notes(runtime, path.as_ptr());
+        assert_ne!(notes_id, 0);
+        md_notes_runtime_close_notes(runtime, notes_id);
+
+        let path = c_path(invalid_book.path());
+        assert_eq!(md_notes_runtime_open_notes(runtime, path.as_ptr()), 0);
+
+        assert_eq!(
+            md_notes_runtime_set_event_callback(runtime, None, ptr::null_mut()),
+            MdNotesStatus::Ok
+        );
+        md_notes_runtime_free(runtime);
+    }
+
+    let events = events.into_inner().unwrap();
+    let kinds: Vec<_> = events.iter().map(|(kind, _, _)| *kind).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            MdNotesEventKind::Loading,
+            MdNotesEventKind::Built,
+            MdNotesEventKind::Closed,
+            MdNotesEventKind::Loading,
+            MdNotesEventKind::BuildFailed,
+        ]
+    );
+
+    // only our failure has a message, and each notes reports under its own id
+    let messages: Vec<_> = events.iter().map(|(_, _, message)| *message).collect();
+    assert_eq!(messages, vec![false, false, false, false, true]);
+    assert!(events[..3].iter().all(|(_, id, _)| *id == events[0].1));
+    assert_ne!(events[3].1, events[0].1);
+}
+
+#[derive(Default)]
+struct SlowCallback {
+    started: AtomicBool,
+    finished: AtomicBool,
+}
+
+extern "C" fn slow_callback(context: *mut c_void, _event: *const MdNotesEvent) {
+    let callback = unsafe { &*(context as *const SlowCallback) };
+
+    callback.started.store(true, Ordering::SeqCst);
+    thread::sleep(Duration::from_millis(300));
+    callback.finished.store(true, Ordering::SeqCst);
+}
+
+#[test]
+fn clearing_the_callback_waits_for_running_calls() {
+    let book = write_book("[book]\ntitle = \"Slow\"\n");
+    let callback = SlowCallback::default();
+
+    unsafe {
+        let runtime = test_runtime();
+        md_notes_runtime_set_event_callback(
+            runtime,
+            Some(slow_callback),
+            &callback as *const _ as *mut c_void,
+        );
+
+        // raw pointers can't cross threads, but our runtime can
+        let shared_runtime = runtime as usize;
+        let path = c_path(book.path());
+        let opening = thread::spawn(move || {
+            let mut notes_id: NotesId = 0;
+            md_notes_runtime_open_notes_v2(
+                shared_runtime as *mut MdNotesRuntime,
+                path.as_ptr(),
+                &mut notes_id,
+            );
+
+            notes_id
+        });
+
+        while !callback.started.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        md_notes_runtime_set_event_callback(runtime, None, ptr::null_mut());
+        // our context could be freed now
+        assert!(callback.finished.load(Ordering::SeqCst));
+
+        let notes_id = opening.join().unwrap();
+        assert_eq!(
+            md_notes_runtime_close_notes_v2(runtime, notes_id),
+            MdNotesStatus::Ok
+        );
+        md_notes_runtime_free(runtime);
+    }
+}
+
+#[test]
+fn reopening_a_book_shares_its_notes_id() {
+    let book = write_book("[book]\ntitle = \"Shared\"\n");
+
+    unsafe {
+        assert_eq!(md_notes_api_version(), MD_NOTES_API_VERSION);
+
+        let runtime = test_runtime();
+        let path = c_path(book.path());
+        // the same book through a different, non canonical path
+        let other_path = c_path(&book.path().join("src/.."));
+
+        let mut first_id: NotesId = 0;
+        let mut second_id: NotesId = 0;
+        assert_eq!(
+            md_notes_runtime_open_notes_v2(runtime, path.as_ptr(), &mut first_id),
+            MdNotesStatus::Ok
+        );
+        assert_eq!(
+            md_notes_runtime_open_notes_v2(runtime, other_path.as_ptr(), &mut second_id),
+            MdNotesStatus::Ok
+        );
+        assert_ne!(first_id, 0);
+        assert_eq!(first_id, second_id);
+
+        // the v1 interface sees the same notes
+        assert_eq!(
+            md_notes_runtime_open_notes(runtime, path.as_ptr()) as NotesId,
+            first_id
+        );
+
+        assert_eq!(
+            md_notes_runtime_open_notes_v2(runtime, path.as_ptr(), ptr::null_mut()),
+            MdNotesStatus::NullPointer
+        );
+
+        // every open needs a close
+        for _ in 0..3 {
+            assert_eq!(
+                md_notes_runtime_close_notes_v2(runtime, first_id),
+                MdNotesStatus::Ok
+            );
+        }
+        assert_eq!(
+            md_notes_runtime_close_notes_v2(runtime, first_id),
+            MdNotesStatus::InvalidNotesId
+        );
+
+        // once closed, the book gets a fresh id that is never reused
+        assert_eq!(
+            md_notes_runtime_open_notes_v2(runtime, path.as_ptr(), &mut second_id),
+            MdNotesStatus::Ok
+        );
+        assert!(second_id > first_id);
+        assert_eq!(
+            md_notes_runtime_close_notes_v2(runtime, second_id),
+            MdNotesStatus::Ok
+        );
+
+        md_notes_runtime_free(runtime);
+    }
+}
+
+#[test]
+fn runtime_options_are_validated() {
+    unsafe {
+        assert!(md_notes_runtime_new_with_options(ptr::null()).is_null());
+        assert_eq!(last_error().0, MdNotesStatus::NullPointer);
+
+        let bad_address = CString::new("not an address").unwrap();
+        let mut options = md_notes_runtime_options_default();
+        options.port_policy = MdNotesPortPolicy::Random;
+        options.skip_environment = true;
+        options.bind_address = bad_address.as_ptr();
+        assert!(md_notes_runtime_new_with_options(&options).is_null());
+        assert_eq!(last_error().0, MdNotesStatus::InvalidArgument);
+
+        let shells = [ptr::null()];
+        let mut options = md_notes_runtime_options_default();
+        options.shells = shells.as_ptr();
+        options.shells_len = shells.len();
+        assert!(md_notes_runtime_new_with_options(&options).is_null());
+        assert_eq!(last_error().0, MdNotesStatus::NullPointer);
+
+        let address = CString::new("127.0.0.1").unwrap();
+        let mut options = md_notes_runtime_options_default();
+        options.port_policy = MdNotesPortPolicy::Random;
+        options.skip_environment = true;
+        options.bind_address = address.as_ptr();
+        options.broadcast_buffer = 1;
+        let runtime = md_notes_runtime_new_with_options(&options);
+        assert!(!runtime.is_null());
+        assert_ne!(md_notes_runtime_server_port(runtime), 0);
+        md_notes_runtime_free(runtime);
+    }
+}
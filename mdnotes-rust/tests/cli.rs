@@ -0,0 +1,125 @@
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+
+use crate::common::{http_get, write_book};
+
+mod common;
+
+fn mdnotes(args: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_mdnotes"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn check_reports_problems_without_touching_the_book() {
+    let good = write_book("[book]\ntitle = \"Good\"\n");
+    let output = mdnotes(&["check", good.path().to_str().unwrap()]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(!good.path().join("book").exists());
+
+    let bad = write_book("[book]\ntitle = \"Bad\"\n");
+    fs::write(
+        bad.path().join("src/SUMMARY.md"),
+        "# Summary\n\n- [Missing](missing.md)\n",
+    )
+    .unwrap();
+    let output = mdnotes(&["check", bad.path().to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("missing.md"));
+    assert!(!bad.path().join("src/missing.md").exists());
+}
+
+#[test]
+fn build_writes_to_the_destination() {
+    let book = write_book("[book]\ntitle = \"Built\"\n");
+    let dest = tempfile::tempdir().unwrap();
+
+    let output = mdnotes(&[
+        "build",
+        "--dest-dir",
+        dest.path().to_str().unwrap(),
+        book.path().to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(dest.path().join("chapter.html").exists());
+}
+
+#[test]
+fn serve_serves_every_book_until_interrupted() {
+    let first = write_book("[book]\ntitle = \"First\"\n");
+    let second = write_book("[book]\ntitle = \"Second\"\n");
+    // keep our cache and settings out of the real home directory
+    let home = tempfile::tempdir().unwrap();
+
+    let mut serving = Command::new(env!("CARGO_BIN_EXE_mdnotes"))
+        .args([
+            "serve",
+            "--bind",
+            "127.0.0.1",
+            first.path().to_str().unwrap(),
+            second.path().to_str().unwrap(),
+        ])
+        .env("HOME", home.path())
+        .env("XDG_CACHE_HOME", home.path().join("cache"))
+        .env("XDG_CONFIG_HOME", home.path().join("config"))
+        .env("XDG_DATA_HOME", home.path().join("data"))
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // each book is announced once it's built, with where it's served
+    let urls: Vec<_> = BufReader::new(serving.stdout.take().unwrap())
+        .lines()
+        .map(Result::unwrap)
+        .filter_map(|line| {
+            line.split(" at http://127.0.0.1:")
+                .nth(1)
+                .map(str::to_string)
+        })
+        .take(2)
+        .collect();
+    assert_eq!(urls.len(), 2);
+
+    for (url, title) in urls.iter().zip(&["First", "Second"]) {
+        let (port, path) = url.split_once('/').unwrap();
+        let response = http_get(
+            port.parse().unwrap(),
+            &format!("/{}chapter.html", path),
+            &[],
+        );
+        assert_eq!(response.status, 200, "{}", url);
+        assert!(String::from_utf8_lossy(&response.body).contains(title));
+    }
+
+    // our port is saved where only the CLI looks for it
+    let port = urls[0].split_once('/').unwrap().0;
+    assert_eq!(
+        fs::read_to_string(home.path().join("data/mdnotes/cli/server-port")).unwrap(),
+        port
+    );
+
+    unsafe {
+        libc::kill(serving.id() as libc::pid_t, libc::SIGINT);
+    }
+    assert!(serving.wait().unwrap().success());
+}
+
+#[test]
+fn bad_arguments_print_usage() {
+    assert_eq!(mdnotes(&[]).status.code(), Some(2));
+    assert_eq!(mdnotes(&["serve"]).status.code(), Some(2));
+    assert_eq!(
+        mdnotes(&["build", "--unknown", "x", "dir"]).status.code(),
+        Some(2)
+    );
+    assert_eq!(
+        mdnotes(&["serve", "--build-timeout", "-1", "dir"])
+            .status
+            .code(),
+        Some(2)
+    );
+    assert!(mdnotes(&["--help"]).status.success());
+}
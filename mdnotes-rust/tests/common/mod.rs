@@ -0,0 +1,270 @@
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tempfile::TempDir;
+use tungstenite::{Message, WebSocket};
+
+use mdnotes::{BuildOutput, EnvironmentSetup, MdNotesRuntime, PortPolicy};
+
+/// A runtime that keeps to itself, on a random port without our login shell environment, that
+/// builds into `build_output`
+pub fn output_runtime(build_output: BuildOutput) -> MdNotesRuntime {
+    MdNotesRuntime::builder()
+        .port(PortPolicy::Random)
+        .environment(EnvironmentSetup::Skip)
+        .build_output(build_output)
+        .build()
+        .unwrap()
+}
+
+/// Run a check against each output we serve from, memory and a cache directory
+pub fn with_every_output<F: Fn(BuildOutput)>(check: F) {
+    check(BuildOutput::Memory);
+
+    let cache_dir = tempfile::tempdir().unwrap();
+    check(BuildOutput::Cache(cache_dir.path().into()));
+}
+
+/// A book opened in a runtime of its own, for requests against its mount
+pub struct ServedBook {
+    pub runtime: MdNotesRuntime,
+    pub port: u16,
+    pub slug: String,
+}
+
+impl ServedBook {
+    pub fn open(book_dir: &Path, build_output: BuildOutput) -> ServedBook {
+        let runtime = output_runtime(build_output);
+        let notes_id = runtime.open_notes(book_dir.into()).unwrap();
+
+        ServedBook {
+            port: runtime.server_port(),
+            slug: runtime.notes_slug(notes_id).unwrap(),
+            runtime,
+        }
+    }
+
+    /// Get one of our book's output files
+    pub fn get(&self, file: &str, headers: &[(&str, &str)]) -> HttpResponse {
+        http_get(
+            self.port,
+            &format!("/{}/static/{}", self.slug, file),
+            headers,
+        )
+    }
+
+    /// Connect to our book's websocket with the stock livereload protocol
+    pub fn websocket(&self) -> WebSocket<TcpStream> {
+        ws_connect(self.port, &format!("/{}/ws", self.slug))
+    }
+}
+
+/// Write a small book with a single chapter into a temporary directory
+pub fn write_book(book_toml: &str) -> TempDir {
+    write_book_named(".tmp", book_toml)
+}
+
+/// Write a small book into a temporary directory whose name starts with `prefix`
+pub fn write_book_named(prefix: &str, book_toml: &str) -> TempDir {
+    let book_dir = tempfile::Builder::new().prefix(prefix).tempdir().unwrap();
+    fs::write(book_dir.path().join("book.toml"), book_toml).unwrap();
+    fs::create_dir(book_dir.path().join("src")).unwrap();
+    fs::write(
+        book_dir.path().join("src/SUMMARY.md"),
+        "# Summary\n\n- [Chapter](chapter.md)\n",
+    )
+    .unwrap();
+    fs::write(book_dir.path().join("src/chapter.md"), "# Chapter\n").unwrap();
+
+    book_dir
+}
+
+/// Write a small book whose builds take at least `seconds`, using a preprocessor that sleeps
+pub fn write_slow_book(title: &str, seconds: f64) -> TempDir {
+    let book_dir = write_book(&format!("[book]\ntitle = \"{}\"\n", title));
+    let script = book_dir.path().join("slow.py");
+    fs::write(
+        &script,
+        format!(
+            "import json, sys, time\n\
+             if len(sys.argv) > 1:\n    sys.exit(0)\n\
+             time.sleep({})\n\
+             print(json.dumps(json.load(sys.stdin)[1]))\n",
+            seconds
+        ),
+    )
+    .unwrap();
+
+    let mut book_toml = fs::read_to_string(book_dir.path().join("book.toml")).unwrap();
+    book_toml.push_str(&format!(
+        "\n[preprocessor.slow]\ncommand = \"python3 {}\"\n",
+        script.display()
+    ));
+    fs::write(book_dir.path().join("book.toml"), book_toml).unwrap();
+
+    book_dir
+}
+
+/// A small book whose builds can be held in a preprocessor until they're released, and that
+/// counts how many builds have reached it
+pub struct GatedBook {
+    pub dir: TempDir,
+}
+
+impl GatedBook {
+    pub fn new(title: &str) -> GatedBook {
+        let dir = write_book(&format!("[book]\ntitle = \"{}\"\n", title));
+        let script = dir.path().join("gated.py");
+        fs::write(
+            &script,
+            "import json, os, sys, time\n\
+             if len(sys.argv) > 1:\n    sys.exit(0)\n\
+             book = json.load(sys.stdin)[1]\n\
+             here = os.path.dirname(os.path.abspath(__file__))\n\
+             with open(os.path.join(here, 'runs'), 'a') as runs:\n    runs.write('.')\n\
+             while os.path.exists(os.path.join(here, 'held')):\n    time.sleep(0.02)\n\
+             print(json.dumps(book))\n",
+        )
+        .unwrap();
+
+        let mut book_toml = fs::read_to_string(dir.path().join("book.toml")).unwrap();
+        book_toml.push_str(&format!(
+            "\n[preprocessor.gated]\ncommand = \"python3 {}\"\n",
+            script.display()
+        ));
+        fs::write(dir.path().join("book.toml"), book_toml).unwrap();
+
+        GatedBook { dir }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Hold every build from here on in our preprocessor
+    pub fn hold(&self) {
+        fs::write(self.path().join("held"), "").unwrap();
+    }
+
+    pub fn release(&self) {
+        fs::remove_file(self.path().join("held")).unwrap();
+    }
+
+    /// How many builds have reached our preprocessor
+    pub fn runs(&self) -> usize {
+        fs::read_to_string(self.path().join("runs"))
+            .map(|runs| runs.len())
+            .unwrap_or(0)
+    }
+
+    /// Wait for this many builds to have reached our preprocessor
+    pub fn wait_for_runs(&self, runs: usize) {
+        wait_for(|| self.runs() >= runs);
+    }
+}
+
+/// Wait for something to happen, failing after longer than any of our builds should take
+pub fn wait_for<F: Fn() -> bool>(done: F) {
+    let start = Instant::now();
+    while !done() {
+        assert!(start.elapsed() < Duration::from_secs(30), "Timed out");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// A bare bones HTTP/1.1 GET so we can see exactly what our server sends back
+pub fn http_get(port: u16, path: &str, headers: &[(&str, &str)]) -> HttpResponse {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+
+    let mut request = format!(
+        "GET {} HTTP/1.1\r\nHost: localhost:{}\r\nConnection: close\r\n",
+        path, port
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).unwrap();
+
+    let mut raw = vec![];
+    stream.read_to_end(&mut raw).unwrap();
+
+    let split = raw
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .expect("We should always get a full response head");
+    let head = String::from_utf8(raw[..split].to_vec()).unwrap();
+    let body = raw[split + 4..].to_vec();
+
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .unwrap()
+        .split(' ')
+        .nth(1)
+        .unwrap()
+        .parse()
+        .unwrap();
+    let headers = lines
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            Some((
+                parts.next()?.trim().to_string(),
+                parts.next()?.trim().to_string(),
+            ))
+        })
+        .collect();
+
+    HttpResponse {
+        status,
+        headers,
+        body,
+    }
+}
+
+/// Connect a websocket that gives up on reads that take longer than a build should
+pub fn ws_connect(port: u16, path: &str) -> WebSocket<TcpStream> {
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(20)))
+        .unwrap();
+
+    let url = format!("ws://localhost:{}{}", port, path);
+    let (websocket, _) = tungstenite::client(url.as_str(), stream).unwrap();
+
+    websocket
+}
+
+/// The next text message, or `None` once the socket is closed
+pub fn ws_next_text(websocket: &mut WebSocket<TcpStream>) -> Option<String> {
+    loop {
+        match websocket.read() {
+            Ok(Message::Text(text)) => return Some(text),
+            Ok(Message::Close(_)) => return None,
+            Ok(_) => (),
+            Err(tungstenite::Error::ConnectionClosed) => return None,
+            Err(e) => panic!("websocket error: {}", e),
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use std::fs;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use mdnotes::{BuildOutput, EnvironmentSetup, FakeWatcher, MdNotesRuntime, PortPolicy, WatchMode};
+
+use crate::common::{http_get, write_book, ws_connect, ws_next_text};
+
+mod common;
+
+fn next_json(websocket: &mut tungstenite::WebSocket<std::net::TcpStream>) -> Value {
+    serde_json::from_str(&ws_next_text(websocket).unwrap()).unwrap()
+}
+
+/// Wait for the next build and return the paths that set it off
+fn next_build(websocket: &mut tungstenite::WebSocket<std::net::TcpStream>) -> Vec<String> {
+    assert_eq!(next_json(websocket)["type"], "build-started");
+    let reload = next_json(websocket);
+    assert_eq!(reload["type"], "reload", "{}", reload);
+
+    reload["changes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|change| change["path"].as_str().unwrap().to_string())
+        .collect()
+}
+
+fn runtime(watcher: &FakeWatcher, mode: WatchMode) -> MdNotesRuntime {
+    MdNotesRuntime::builder()
+        .port(PortPolicy::Random)
+        .environment(EnvironmentSetup::Skip)
+        .build_output(BuildOutput::Memory)
+        .watcher(watcher.clone())
+        .watch_mode(mode)
+        .poll_interval(Duration::from_millis(100))
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn injected_changes_rebuild_and_broadcast() {
+    let book = write_book("[book]\ntitle = \"Injected\"\n");
+    let src = book.path().join("src");
+    let watcher = FakeWatcher::default();
+    let runtime = runtime(&watcher, WatchMode::Native);
+    let port = runtime.server_port();
+    runtime.open_notes(book.path().into()).unwrap();
+
+    assert!(watcher.is_watched(src.join("chapter.md")));
+    assert!(watcher.is_watched(book.path().join("book.toml")));
+    assert!(!watcher.is_watched(book.path().join("elsewhere/other.md")));
+
+    let mut websocket = ws_connect(port, "/injected/ws?protocol=json");
+
+    fs::write(src.join("chapter.md"), "# Injected Chapter\n").unwrap();
+    watcher.write(src.join("chapter.md"));
+    let reload = {
+        assert_eq!(next_json(&mut websocket)["type"], "build-started");
+        next_json(&mut websocket)
+    };
+    assert_eq!(
+        reload["changes"],
+        json!([{"path": "src/chapter.md", "url": "/injected/static/chapter.html"}])
+    );
+    let response = http_get(port, "/injected/static/chapter.html", &[]);
+    assert!(String::from_utf8_lossy(&response.body).contains("Injected Chapter"));
+
+    fs::write(src.join("draft.md"), "# Draft\n").unwrap();
+    watcher.create(src.join("draft.md"));
+    assert_eq!(next_build(&mut websocket), vec!["src/draft.md"]);
+
+    fs::rename(src.join("draft.md"), src.join("extra.md")).unwrap();
+    watcher.rename(src.join("draft.md"), src.join("extra.md"));
+    assert_eq!(
+        next_build(&mut websocket),
+        vec!["src/draft.md", "src/extra.md"]
+    );
+
+    fs::remove_file(src.join("extra.md")).unwrap();
+    watcher.remove(src.join("extra.md"));
+    assert_eq!(next_build(&mut websocket), vec!["src/extra.md"]);
+
+    // moving a file out of our book removes it just the same
+    let elsewhere = tempfile::tempdir().unwrap();
+    fs::write(src.join("leaving.md"), "# Leaving\n").unwrap();
+    watcher.create(src.join("leaving.md"));
+    assert_eq!(next_build(&mut websocket), vec!["src/leaving.md"]);
+
+    fs::rename(src.join("leaving.md"), elsewhere.path().join("leaving.md")).unwrap();
+    watcher.rename(src.join("leaving.md"), elsewhere.path().join("leaving.md"));
+    assert_eq!(next_build(&mut websocket), vec!["src/leaving.md"]);
+}
+
+#[test]
+fn injected_changes_follow_ignore_files() {
+    let book = write_book("[book]\ntitle = \"Filtered\"\n");
+    fs::write(book.path().join(".gitignore"), "*.tmp\n").unwrap();
+    let src = book.path().join("src");
+    let watcher = FakeWatcher::default();
+    let runtime = runtime(&watcher, WatchMode::Native);
+    let port = runtime.server_port();
+    runtime.open_notes(book.path().into()).unwrap();
+
+    let mut websocket = ws_connect(port, "/filtered/ws?protocol=json");
+
+    watcher.write(src.join("scratch.tmp"));
+    watcher.write(book.path().join("outside.md"));
+    watcher.write(src.join("chapter.md"));
+    assert_eq!(next_build(&mut websocket), vec!["src/chapter.md"]);
+}
+
+#[test]
+fn injected_rescans_and_errors_reach_our_clients() {
+    let book = write_book("[book]\ntitle = \"Rescanned\"\n");
+    let watcher = FakeWatcher::default();
+    let runtime = runtime(&watcher, WatchMode::Native);
+    let port = runtime.server_port();
+    runtime.open_notes(book.path().into()).unwrap();
+
+    let mut websocket = ws_connect(port, "/rescanned/ws?protocol=json");
+
+    watcher.rescan();
+    assert_eq!(next_json(&mut websocket)["type"], "build-started");
+    assert_eq!(next_json(&mut websocket)["type"], "full-reload");
+
+    watcher.error("the share went away");
+    let failure = next_json(&mut websocket);
+    assert_eq!(failure["type"], "watch-failed");
+    assert!(failure["message"]
+        .as_str()
+        .unwrap()
+        .contains("the share went away"));
+}
+
+#[test]
+fn failing_native_watches_fall_back_on_polling() {
+    let book = write_book("[book]\ntitle = \"Unwatchable\"\n");
+    let watcher = FakeWatcher::default();
+    watcher.set_failing(true);
+    let runtime = runtime(&watcher, WatchMode::Auto);
+    let port = runtime.server_port();
+    runtime.open_notes(book.path().into()).unwrap();
+
+    assert!(!watcher.is_watched(book.path().join("src/chapter.md")));
+
+    let mut websocket = ws_connect(port, "/unwatchable/ws?protocol=json");
+
+    fs::write(book.path().join("src/chapter.md"), "# Polled\n").unwrap();
+    assert_eq!(next_build(&mut websocket), vec!["src/chapter.md"]);
+}
+
+#[test]
+fn silent_native_watchers_fall_back_on_polling() {
+    let book = write_book("[book]\ntitle = \"Silent\"\n");
+    // our fake never sends anything unless we tell it to, like a network share
+    let watcher = FakeWatcher::default();
+    let runtime = runtime(&watcher, WatchMode::Auto);
+    let port = runtime.server_port();
+    runtime.open_notes(book.path().into()).unwrap();
+
+    let mut websocket = ws_connect(port, "/silent/ws?protocol=json");
+
+    fs::write(book.path().join("src/chapter.md"), "# Missed\n").unwrap();
+    assert_eq!(next_build(&mut websocket), vec!["src/chapter.md"]);
+
+    // from here on we're polling
+    fs::write(book.path().join("src/chapter.md"), "# Polled Again\n").unwrap();
+    assert_eq!(next_build(&mut websocket), vec!["src/chapter.md"]);
+    let response = http_get(port, "/silent/static/chapter.html", &[]);
+    assert!(String::from_utf8_lossy(&response.body).contains("Polled Again"));
+}
+
+#[test]
+fn removed_books_are_orphaned_without_another_event() {
+    let book = write_book("[book]\ntitle = \"Vanished\"\n");
+    let watcher = FakeWatcher::default();
+    let runtime = runtime(&watcher, WatchMode::Native);
+    let port = runtime.server_port();
+    let notes_id = runtime.open_notes(book.path().into()).unwrap();
+
+    let mut websocket = ws_connect(port, "/vanished/ws?protocol=json");
+
+    // our fake never says a word about it
+    fs::remove_dir_all(book.path()).unwrap();
+    assert_eq!(next_json(&mut websocket)["type"], "notebook-orphaned");
+    assert_eq!(runtime.notes_orphaned(notes_id), Some(true));
+}
+
+#[test]
+fn removing_the_book_root_orphans_it() {
+    let book = write_book("[book]\ntitle = \"Uprooted\"\n");
+    let watcher = FakeWatcher::default();
+    let runtime = runtime(&watcher, WatchMode::Native);
+    let notes_id = runtime.open_notes(book.path().into()).unwrap();
+
+    fs::remove_dir_all(book.path()).unwrap();
+    watcher.remove(book.path());
+    assert_eq!(runtime.notes_orphaned(notes_id), Some(true));
+}
@@ -0,0 +1,305 @@
+use std::fs;
+use std::io::Read;
+
+use mdnotes::BuildOutput;
+
+use crate::common::{with_every_output, write_book, ws_next_text, HttpResponse, ServedBook};
+
+mod common;
+
+/// A book with a binary attachment whose bytes are their own offsets
+fn write_attachment_book(title: &str) -> (tempfile::TempDir, Vec<u8>) {
+    let book = write_book(&format!("[book]\ntitle = \"{}\"\n", title));
+    let attachment: Vec<u8> = (0..200).collect();
+    fs::write(book.path().join("src/attachment.bin"), &attachment).unwrap();
+
+    (book, attachment)
+}
+
+#[test]
+fn ranges_are_served_from_every_output() {
+    with_every_output(check_ranges);
+}
+
+/// The range we ask for, and the status, content range and body we get back
+type RangeCase<'a> = (&'a str, u16, Option<&'a str>, Option<&'a [u8]>);
+
+fn check_ranges(build_output: BuildOutput) {
+    let (book, attachment) = write_attachment_book("Ranges");
+    let served = ServedBook::open(book.path(), build_output);
+    let get = |headers: &[(&str, &str)]| served.get("attachment.bin", headers);
+
+    let full = get(&[]);
+    assert_eq!(full.status, 200);
+    assert_eq!(full.header("accept-ranges"), Some("bytes"));
+    assert_eq!(full.body, attachment);
+
+    let single_ranges: &[RangeCase] = &[
+        (
+            "bytes=10-19",
+            206,
+            Some("bytes 10-19/200"),
+            Some(&attachment[10..20]),
+        ),
+        (
+            "bytes=-5",
+            206,
+            Some("bytes 195-199/200"),
+            Some(&attachment[195..]),
+        ),
+        // ranges past the end are cut short
+        (
+            "bytes=190-500",
+            206,
+            Some("bytes 190-199/200"),
+            Some(&attachment[190..]),
+        ),
+        ("bytes=200-", 416, Some("bytes */200"), None),
+        // malformed ranges are ignored
+        ("bytes=banana", 200, None, Some(&attachment)),
+    ];
+    for (range, status, content_range, body) in single_ranges {
+        let response = get(&[("Range", range)]);
+        assert_eq!(response.status, *status, "{}", range);
+        assert_eq!(
+            response.header("content-range"),
+            *content_range,
+            "{}",
+            range
+        );
+        if let Some(body) = body {
+            assert_eq!(response.body, *body, "{}", range);
+            assert_eq!(
+                response.header("content-length"),
+                Some(body.len().to_string().as_str()),
+                "{}",
+                range
+            );
+        }
+    }
+
+    check_multiple_ranges(&get(&[("Range", "bytes=0-1, 100-102")]), &attachment);
+
+    // ranges are only honored while the file is the version the client already has
+    let last_modified = full.header("last-modified").unwrap();
+    let etag = full.header("etag").unwrap();
+    let if_ranges = &[
+        (last_modified, 206),
+        (etag, 206),
+        ("Wed, 21 Oct 2015 07:28:00 GMT", 200),
+        ("\"something-else\"", 200),
+    ];
+    for (if_range, status) in if_ranges {
+        let response = get(&[("Range", "bytes=0-9"), ("If-Range", if_range)]);
+        assert_eq!(response.status, *status, "{}", if_range);
+        let expected: &[u8] = if *status == 206 {
+            &attachment[..10]
+        } else {
+            &attachment
+        };
+        assert_eq!(response.body, expected, "{}", if_range);
+    }
+}
+
+fn check_multiple_ranges(multiple: &HttpResponse, attachment: &[u8]) {
+    assert_eq!(multiple.status, 206);
+    let content_type = multiple.header("content-type").unwrap();
+    let boundary = content_type
+        .strip_prefix("multipart/byteranges; boundary=")
+        .unwrap();
+    assert_eq!(
+        multiple.header("content-length"),
+        Some(multiple.body.len().to_string().as_str())
+    );
+
+    let mut expected = vec![];
+    for (start, end) in &[(0, 2), (100, 103)] {
+        expected.extend_from_slice(
+            format!(
+                "\r\n--{}\r\nContent-Type: application/octet-stream\r\n\
+                 Content-Range: bytes {}-{}/200\r\n\r\n",
+                boundary,
+                start,
+                end - 1
+            )
+            .as_bytes(),
+        );
+        expected.extend_from_slice(&attachment[*start..*end]);
+    }
+    expected.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    assert_eq!(multiple.body, expected);
+}
+
+#[test]
+fn unchanged_files_are_not_sent_again() {
+    with_every_output(check_conditionals);
+}
+
+fn check_conditionals(build_output: BuildOutput) {
+    let book = write_book("[book]\ntitle = \"Conditional\"\n");
+    let served = ServedBook::open(book.path(), build_output);
+    let css = "css/general.css";
+
+    let full = served.get(css, &[]);
+    assert_eq!(full.status, 200);
+    let etag = full.header("etag").unwrap();
+    let last_modified = full.header("last-modified").unwrap();
+    assert!(!etag.starts_with("W/"), "our etags should be strong");
+
+    let any_of = format!("\"something-else\", {}", etag);
+    let conditionals: &[(&[(&str, &str)], u16)] = &[
+        (&[("If-None-Match", etag)], 304),
+        (&[("If-None-Match", &any_of)], 304),
+        (&[("If-Modified-Since", last_modified)], 304),
+        // etags win over dates
+        (
+            &[
+                ("If-None-Match", "\"something-else\""),
+                ("If-Modified-Since", last_modified),
+            ],
+            200,
+        ),
+    ];
+    for (headers, status) in conditionals {
+        let response = served.get(css, headers);
+        assert_eq!(response.status, *status, "{:?}", headers);
+        if *status == 304 {
+            assert!(response.body.is_empty());
+            assert_eq!(response.header("etag"), Some(etag));
+        } else {
+            assert_eq!(response.body, full.body);
+        }
+    }
+
+    let page = served.get("chapter.html", &[]);
+    let page_etag = page.header("etag").unwrap();
+
+    let mut websocket = served.websocket();
+    fs::write(book.path().join("src/chapter.md"), "# Rewritten\n").unwrap();
+    assert_eq!(ws_next_text(&mut websocket).unwrap(), "reload");
+
+    // only what the rebuild changed is sent again
+    assert_eq!(served.get(css, &[("If-None-Match", etag)]).status, 304);
+    let rewritten = served.get("chapter.html", &[("If-None-Match", page_etag)]);
+    assert_eq!(rewritten.status, 200);
+    assert_ne!(rewritten.header("etag"), Some(page_etag));
+    assert!(String::from_utf8_lossy(&rewritten.body).contains("Rewritten"));
+}
+
+#[test]
+fn text_is_compressed_for_clients_that_accept_it() {
+    with_every_output(check_compression);
+}
+
+fn decode(encoding: Option<&str>, body: &[u8]) -> Vec<u8> {
+    let mut decoded = vec![];
+    match encoding {
+        Some("gzip") => flate2::read::GzDecoder::new(body)
+            .read_to_end(&mut decoded)
+            .unwrap(),
+        Some("br") => brotli::Decompressor::new(body, 4096)
+            .read_to_end(&mut decoded)
+            .unwrap(),
+        Some(other) => panic!("Unexpected encoding {}", other),
+        None => return body.to_vec(),
+    };
+
+    decoded
+}
+
+fn check_compression(build_output: BuildOutput) {
+    let (book, attachment) = write_attachment_book("Compressed");
+    fs::write(
+        book.path().join("src/chapter.md"),
+        format!(
+            "# Chapter\n\n{}",
+            "Repeated words compress well. ".repeat(200)
+        ),
+    )
+    .unwrap();
+    let served = ServedBook::open(book.path(), build_output);
+    let chapter = "chapter.html";
+
+    let plain = served.get(chapter, &[]);
+    assert_eq!(plain.header("vary"), Some("accept-encoding"));
+    let plain_etag = plain.header("etag").unwrap();
+
+    // what our client accepts and the encoding we pick
+    let negotiations = &[
+        (None, None),
+        (Some("gzip"), Some("gzip")),
+        (Some("gzip, deflate, br"), Some("br")),
+        (Some("br;q=0.5, gzip"), Some("gzip")),
+        (Some("gzip;q=0, identity"), None),
+    ];
+    for (accepted, encoding) in negotiations {
+        let headers: Vec<_> = accepted
+            .iter()
+            .map(|accepted| ("Accept-Encoding", *accepted))
+            .collect();
+        let response = served.get(chapter, &headers);
+        assert_eq!(response.status, 200, "{:?}", accepted);
+        assert_eq!(
+            response.header("content-encoding"),
+            *encoding,
+            "{:?}",
+            accepted
+        );
+        assert_eq!(response.header("vary"), Some("accept-encoding"));
+        assert_eq!(
+            response.header("content-length"),
+            Some(response.body.len().to_string().as_str())
+        );
+        assert_eq!(decode(*encoding, &response.body), plain.body);
+        if encoding.is_some() {
+            assert!(response.body.len() < plain.body.len());
+            assert_ne!(response.header("etag"), Some(plain_etag));
+        }
+    }
+
+    // each encoding is its own version of our file
+    let gzipped = served.get(chapter, &[("Accept-Encoding", "gzip")]);
+    let gzip_etag = gzipped.header("etag").unwrap();
+    let revalidations = &[("gzip", 304), ("br", 200)];
+    for (encoding, status) in revalidations {
+        let response = served.get(
+            chapter,
+            &[("Accept-Encoding", encoding), ("If-None-Match", gzip_etag)],
+        );
+        assert_eq!(response.status, *status, "{}", encoding);
+        assert_eq!(response.header("vary"), Some("accept-encoding"));
+    }
+
+    // ranges are always of the file as it is
+    let range = served.get(
+        chapter,
+        &[("Accept-Encoding", "gzip"), ("Range", "bytes=0-9")],
+    );
+    assert_eq!(range.status, 206);
+    assert_eq!(range.header("content-encoding"), None);
+    assert_eq!(range.body, &plain.body[..10]);
+
+    // binary files aren't worth it
+    let binary = served.get("attachment.bin", &[("Accept-Encoding", "gzip")]);
+    assert_eq!(binary.header("content-encoding"), None);
+    assert_eq!(binary.header("vary"), None);
+    assert_eq!(binary.body, attachment);
+
+    // rebuilds replace what we compressed
+    let mut websocket = served.websocket();
+    fs::write(
+        book.path().join("src/chapter.md"),
+        format!(
+            "# Chapter\n\n{}",
+            "Rebuilt words compress well. ".repeat(200)
+        ),
+    )
+    .unwrap();
+    assert_eq!(ws_next_text(&mut websocket).unwrap(), "reload");
+
+    let rebuilt = served.get(chapter, &[("Accept-Encoding", "gzip")]);
+    assert_ne!(rebuilt.header("etag"), Some(gzip_etag));
+    assert!(
+        String::from_utf8_lossy(&decode(Some("gzip"), &rebuilt.body)).contains("Rebuilt words")
+    );
+}
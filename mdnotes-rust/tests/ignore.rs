@@ -0,0 +1,135 @@
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use mdnotes::{BuildOutput, EnvironmentSetup, MdNotesRuntime, PortPolicy};
+
+use crate::common::{write_book, ws_connect, ws_next_text};
+
+mod common;
+
+fn next_json(websocket: &mut tungstenite::WebSocket<std::net::TcpStream>) -> Value {
+    serde_json::from_str(&ws_next_text(websocket).unwrap()).unwrap()
+}
+
+fn changed_paths(reload: &Value) -> Vec<&str> {
+    assert_eq!(reload["type"], "reload", "{}", reload);
+
+    reload["changes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|change| change["path"].as_str().unwrap())
+        .collect()
+}
+
+#[test]
+fn ignored_changes_follow_every_ignore_file() {
+    // our book is the root of its own repository
+    let book = write_book("[book]\ntitle = \"Ignored\"\n");
+    fs::create_dir_all(book.path().join(".git/info")).unwrap();
+    fs::write(book.path().join(".git/info/exclude"), "*.swp\n").unwrap();
+    fs::write(book.path().join(".gitignore"), "*.tmp\n/src/drafts/\n").unwrap();
+    fs::write(book.path().join("src/.gitignore"), "!important.tmp\n").unwrap();
+    fs::write(book.path().join(".mdnotesignore"), "scratch.md\n").unwrap();
+    fs::create_dir_all(book.path().join("src/drafts")).unwrap();
+
+    let runtime = MdNotesRuntime::builder()
+        .port(PortPolicy::Random)
+        .environment(EnvironmentSetup::Skip)
+        .build_output(BuildOutput::Memory)
+        .build()
+        .unwrap();
+    let port = runtime.server_port();
+    runtime.open_notes(book.path().into()).unwrap();
+
+    let mut websocket = ws_connect(port, "/ignored/ws?protocol=json");
+
+    for ignored in &[
+        "src/.chapter.md.swp",
+        "src/other.tmp",
+        "src/scratch.md",
+        "src/drafts/important.tmp",
+    ] {
+        fs::write(book.path().join(ignored), "ignored").unwrap();
+    }
+    thread::sleep(Duration::from_millis(500));
+
+    // a deeper negation wins over the repository's rule
+    fs::write(book.path().join("src/important.tmp"), "important").unwrap();
+
+    assert_eq!(next_json(&mut websocket)["type"], "build-started");
+    assert_eq!(
+        changed_paths(&next_json(&mut websocket)),
+        vec!["src/important.tmp"]
+    );
+
+    // ignore files are picked up again as soon as they change
+    fs::write(book.path().join("src/.gitignore"), "# nothing special\n").unwrap();
+
+    assert_eq!(next_json(&mut websocket)["type"], "build-started");
+    assert_eq!(
+        changed_paths(&next_json(&mut websocket)),
+        vec!["src/.gitignore"]
+    );
+
+    fs::write(book.path().join("src/important.tmp"), "not anymore").unwrap();
+    thread::sleep(Duration::from_millis(500));
+    fs::write(book.path().join("src/chapter.md"), "# Changed\n").unwrap();
+
+    assert_eq!(next_json(&mut websocket)["type"], "build-started");
+    assert_eq!(
+        changed_paths(&next_json(&mut websocket)),
+        vec!["src/chapter.md"]
+    );
+}
+
+#[test]
+fn worktrees_and_global_excludes_are_followed() {
+    // git's default global excludes file, with nothing in our home directory to point elsewhere
+    let home = tempfile::tempdir().unwrap();
+    fs::create_dir_all(home.path().join(".config/git")).unwrap();
+    fs::write(home.path().join(".config/git/ignore"), "*.bak\n").unwrap();
+    std::env::set_var("HOME", home.path());
+    std::env::remove_var("XDG_CONFIG_HOME");
+    std::env::remove_var("GIT_CONFIG_GLOBAL");
+
+    // our book is a worktree, whose `.git` file points at its git directory elsewhere
+    let repo = tempfile::tempdir().unwrap();
+    let git_dir = repo.path().join("worktrees/ignored");
+    fs::create_dir_all(&git_dir).unwrap();
+    fs::write(git_dir.join("commondir"), "../..\n").unwrap();
+    fs::create_dir_all(repo.path().join("info")).unwrap();
+    fs::write(repo.path().join("info/exclude"), "*.swp\n").unwrap();
+
+    let book = write_book("[book]\ntitle = \"Worktree\"\n");
+    fs::write(
+        book.path().join(".git"),
+        format!("gitdir: {}\n", git_dir.display()),
+    )
+    .unwrap();
+
+    let runtime = MdNotesRuntime::builder()
+        .port(PortPolicy::Random)
+        .environment(EnvironmentSetup::Skip)
+        .build_output(BuildOutput::Memory)
+        .build()
+        .unwrap();
+    let port = runtime.server_port();
+    runtime.open_notes(book.path().into()).unwrap();
+
+    let mut websocket = ws_connect(port, "/worktree/ws?protocol=json");
+
+    fs::write(book.path().join("src/chapter.md.bak"), "ignored").unwrap();
+    fs::write(book.path().join("src/.chapter.md.swp"), "ignored").unwrap();
+    thread::sleep(Duration::from_millis(500));
+    fs::write(book.path().join("src/chapter.md"), "# Changed\n").unwrap();
+
+    assert_eq!(next_json(&mut websocket)["type"], "build-started");
+    assert_eq!(
+        changed_paths(&next_json(&mut websocket)),
+        vec!["src/chapter.md"]
+    );
+}
@@ -0,0 +1,156 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use mdnotes::{BuildIsolation, BuildOutput, EnvironmentSetup, MdNotesRuntime, PortPolicy};
+
+use crate::common::{http_get, write_book, write_slow_book, ws_connect, ws_next_text};
+
+mod common;
+
+fn isolated_runtime(build_output: BuildOutput, timeout: Duration) -> MdNotesRuntime {
+    MdNotesRuntime::builder()
+        .port(PortPolicy::Random)
+        .environment(EnvironmentSetup::Skip)
+        .build_output(build_output)
+        .build_isolation(BuildIsolation::Process {
+            worker: PathBuf::from(env!("CARGO_BIN_EXE_mdnotes")),
+            timeout,
+        })
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn isolated_builds_reload_like_in_process_builds() {
+    let cache_dir = tempfile::tempdir().unwrap();
+    let outputs = vec![
+        BuildOutput::Cache(cache_dir.path().into()),
+        BuildOutput::Memory,
+    ];
+
+    for build_output in outputs {
+        let book = write_book("[book]\ntitle = \"Isolated\"\n");
+        let runtime = isolated_runtime(build_output.clone(), Duration::from_secs(30));
+        let port = runtime.server_port();
+        runtime.open_notes(book.path().into()).unwrap();
+
+        let response = http_get(port, "/isolated/static/chapter.html", &[]);
+        assert_eq!(response.status, 200, "{:?}", build_output);
+
+        let mut websocket = ws_connect(port, "/isolated/ws?protocol=json");
+        fs::write(book.path().join("src/chapter.md"), "# Rebuilt Elsewhere\n").unwrap();
+
+        assert!(ws_next_text(&mut websocket)
+            .unwrap()
+            .contains("build-started"));
+        let reload = ws_next_text(&mut websocket).unwrap();
+        assert!(reload.contains("\"type\":\"reload\""), "{}", reload);
+
+        let response = http_get(port, "/isolated/static/chapter.html", &[]);
+        assert!(
+            String::from_utf8_lossy(&response.body).contains("Rebuilt Elsewhere"),
+            "{:?}",
+            build_output
+        );
+    }
+}
+
+#[test]
+fn hung_builds_are_stopped_and_reported() {
+    let book = write_slow_book("Hung", 0.0);
+    let runtime = isolated_runtime(BuildOutput::Memory, Duration::from_secs(1));
+    let port = runtime.server_port();
+    runtime.open_notes(book.path().into()).unwrap();
+
+    let mut websocket = ws_connect(port, "/hung/ws?protocol=json");
+
+    // our preprocessor hangs from now on, and changing it rebuilds our book
+    let script = book.path().join("slow.py");
+    let hanging = fs::read_to_string(&script)
+        .unwrap()
+        .replace("time.sleep(0)", "time.sleep(600)");
+    fs::write(&script, hanging).unwrap();
+
+    assert!(ws_next_text(&mut websocket)
+        .unwrap()
+        .contains("build-started"));
+    let start = Instant::now();
+    let failure = ws_next_text(&mut websocket).unwrap();
+    assert!(failure.contains("\"type\":\"build-failed\""), "{}", failure);
+    assert!(failure.contains("didn't finish within 1.0s"), "{}", failure);
+    assert!(start.elapsed() < Duration::from_secs(10));
+
+    // our last good build is still served, along with what went wrong
+    let response = http_get(port, "/hung/static/chapter.html", &[]);
+    let body = String::from_utf8_lossy(&response.body);
+    assert!(body.contains("mdnotes-build-error"), "{}", body);
+    assert!(body.contains("Chapter"), "{}", body);
+}
+
+#[test]
+fn closing_notes_stops_their_build_and_frees_its_turn() {
+    let hung = write_slow_book("Closed", 0.0);
+    let other = write_book("[book]\ntitle = \"Waiting\"\n");
+    let runtime = MdNotesRuntime::builder()
+        .port(PortPolicy::Random)
+        .environment(EnvironmentSetup::Skip)
+        .build_output(BuildOutput::Memory)
+        .build_isolation(BuildIsolation::Process {
+            worker: PathBuf::from(env!("CARGO_BIN_EXE_mdnotes")),
+            timeout: Duration::from_secs(600),
+        })
+        .max_concurrent_builds(1)
+        .build()
+        .unwrap();
+    let port = runtime.server_port();
+    let hung_id = runtime.open_notes(hung.path().into()).unwrap();
+    runtime.open_notes(other.path().into()).unwrap();
+
+    let mut hung_websocket = ws_connect(port, "/closed/ws?protocol=json");
+    let script = hung.path().join("slow.py");
+    let hanging = fs::read_to_string(&script)
+        .unwrap()
+        .replace("time.sleep(0)", "time.sleep(600)");
+    fs::write(&script, hanging).unwrap();
+    assert!(ws_next_text(&mut hung_websocket)
+        .unwrap()
+        .contains("build-started"));
+
+    let start = Instant::now();
+    runtime.close_notes(hung_id).unwrap();
+
+    // our other notes get their turn without waiting out the hung build's timeout
+    let mut websocket = ws_connect(port, "/waiting/ws?protocol=json");
+    fs::write(other.path().join("src/chapter.md"), "# Not Kept Waiting\n").unwrap();
+    assert!(ws_next_text(&mut websocket)
+        .unwrap()
+        .contains("build-started"));
+    let reload = ws_next_text(&mut websocket).unwrap();
+    assert!(reload.contains("\"type\":\"reload\""), "{}", reload);
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn missing_workers_fail_to_open_notes() {
+    let book = write_book("[book]\ntitle = \"No Worker\"\n");
+    let runtime = MdNotesRuntime::builder()
+        .port(PortPolicy::Random)
+        .environment(EnvironmentSetup::Skip)
+        .build_output(BuildOutput::Memory)
+        .build_isolation(BuildIsolation::Process {
+            worker: book.path().join("missing-worker"),
+            timeout: Duration::from_secs(5),
+        })
+        .build()
+        .unwrap();
+
+    let error = runtime.open_notes(book.path().into()).unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .contains("Couldn't start our build worker"),
+        "{}",
+        error
+    );
+}
@@ -0,0 +1,115 @@
+use std::fs;
+use std::os::unix::fs::symlink;
+
+use proptest::prelude::*;
+use proptest::test_runner::{Config, TestRunner};
+
+use mdnotes::{BuildOutput, EnvironmentSetup, MdNotesRuntime, PortPolicy};
+
+use crate::common::{http_get, write_book};
+
+mod common;
+
+const SECRET: &str = "TOP SECRET";
+
+/// A book built into its own `book/` directory, with symlinks in its output that lead both
+/// inside and outside of it
+fn symlinked_book() -> (MdNotesRuntime, tempfile::TempDir, tempfile::TempDir) {
+    let outside = tempfile::tempdir().unwrap();
+    fs::write(outside.path().join("secret.txt"), SECRET).unwrap();
+
+    let book = write_book(&format!("# {}\n[book]\ntitle = \"Paths\"\n", SECRET));
+    let runtime = MdNotesRuntime::builder()
+        .port(PortPolicy::Random)
+        .environment(EnvironmentSetup::Skip)
+        .build_output(BuildOutput::Book)
+        .build()
+        .unwrap();
+    runtime.open_notes(book.path().into()).unwrap();
+
+    let html_dir = book.path().join("book");
+    symlink(outside.path(), html_dir.join("escape")).unwrap();
+    symlink(outside.path().join("secret.txt"), html_dir.join("leak.txt")).unwrap();
+    symlink(html_dir.join("chapter.html"), html_dir.join("alias.html")).unwrap();
+
+    (runtime, book, outside)
+}
+
+#[test]
+fn symlinks_and_crafted_paths_stay_inside_our_output() {
+    let (runtime, _book, _outside) = symlinked_book();
+    let port = runtime.server_port();
+    let get = |path: &str| http_get(port, &format!("/paths/static/{}", path), &[]);
+
+    // links inside of our output still work
+    let alias = get("alias.html");
+    assert_eq!(alias.status, 200);
+    assert!(String::from_utf8_lossy(&alias.body).contains("Chapter"));
+
+    for escaping in &[
+        "leak.txt",
+        "escape/secret.txt",
+        "escape/missing.txt",
+        "escape/",
+        "escape",
+        "..%2Fbook.toml",
+        "%2e%2e/book.toml",
+        "%2F..%2Fbook.toml",
+        "%2Fetc%2Fpasswd",
+        "chapter.html%00.txt",
+        "%00",
+        "..%5Cbook.toml",
+    ] {
+        let response = get(escaping);
+        assert_eq!(response.status, 404, "{}", escaping);
+        assert!(!String::from_utf8_lossy(&response.body).contains(SECRET));
+    }
+}
+
+fn segment() -> impl Strategy<Value = String> {
+    prop_oneof![
+        Just("..".to_string()),
+        Just("%2e%2e".to_string()),
+        Just(".".to_string()),
+        Just("".to_string()),
+        Just("%2F".to_string()),
+        Just("%2Fetc".to_string()),
+        Just("%00".to_string()),
+        Just("%5C".to_string()),
+        Just("escape".to_string()),
+        Just("leak.txt".to_string()),
+        Just("secret.txt".to_string()),
+        Just("book.toml".to_string()),
+        Just("chapter.html".to_string()),
+        Just("alias.html".to_string()),
+        "[a-z.]{1,6}",
+        "%[0-9A-F]{2}",
+    ]
+}
+
+#[test]
+fn no_path_reaches_outside_of_our_output() {
+    let (runtime, _book, _outside) = symlinked_book();
+    let port = runtime.server_port();
+
+    let paths = prop::collection::vec(segment(), 1..6).prop_map(|segments| segments.join("/"));
+    TestRunner::new(Config::with_cases(256))
+        .run(&paths, |path| {
+            let response = http_get(port, &format!("/paths/static/{}", path), &[]);
+
+            prop_assert!(
+                [200, 400, 404].contains(&response.status),
+                "{} got a {}",
+                path,
+                response.status
+            );
+            prop_assert!(
+                !String::from_utf8_lossy(&response.body).contains(SECRET),
+                "{} leaked our secret",
+                path
+            );
+
+            Ok(())
+        })
+        .unwrap();
+}
@@ -0,0 +1,102 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use mdbook::MDBook;
+
+use mdnotes::{BuildOutput, EnvironmentSetup, MdNotesRuntime, PortPolicy};
+
+use crate::common::{write_book, ws_connect, ws_next_text};
+
+mod common;
+
+fn modified(path: &Path) -> SystemTime {
+    fs::metadata(path).unwrap().modified().unwrap()
+}
+
+fn read(path: &Path) -> String {
+    fs::read_to_string(path).unwrap()
+}
+
+/// The rendered chapter of a page, without the whitespace our templates add around it
+fn main_content(html: &str) -> String {
+    let start = html.find("<main>").unwrap() + "<main>".len();
+    let end = html.rfind("</main>").unwrap();
+
+    html[start..end].trim().to_string()
+}
+
+#[test]
+fn changed_chapters_are_rendered_on_their_own() {
+    let cache_dir = tempfile::tempdir().unwrap();
+    let book = write_book("[book]\ntitle = \"Rebuild\"\n");
+    fs::write(
+        book.path().join("src/SUMMARY.md"),
+        "# Summary\n\n- [Chapter](chapter.md)\n- [Other](other.md)\n",
+    )
+    .unwrap();
+    fs::write(book.path().join("src/other.md"), "# Other\n").unwrap();
+
+    let runtime = MdNotesRuntime::builder()
+        .port(PortPolicy::Random)
+        .environment(EnvironmentSetup::Skip)
+        .build_output(BuildOutput::Cache(cache_dir.path().into()))
+        .build()
+        .unwrap();
+    let port = runtime.server_port();
+    runtime.open_notes(book.path().into()).unwrap();
+
+    let html_dir: PathBuf = fs::read_dir(cache_dir.path())
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    let other_built = modified(&html_dir.join("other.html"));
+
+    let mut websocket = ws_connect(port, "/rebuild/ws");
+
+    // make sure a full build would give the other chapter a new modified time
+    thread::sleep(Duration::from_millis(50));
+    fs::write(
+        book.path().join("src/chapter.md"),
+        "# Rewritten\n\n## Rewritten\n\nA searchable-word\n\n```rust\nlet x = 1;\n```\n",
+    )
+    .unwrap();
+    assert_eq!(ws_next_text(&mut websocket).unwrap(), "reload");
+
+    assert_eq!(modified(&html_dir.join("other.html")), other_built);
+    assert!(read(&html_dir.join("print.html")).contains("searchable-word"));
+    assert!(read(&html_dir.join("searchindex.json")).contains("searchable"));
+
+    // our chapter should look just like it would from a full build
+    let full_dir = tempfile::tempdir().unwrap();
+    let mut full = MDBook::load(book.path()).unwrap();
+    full.config.build.build_dir = full_dir.path().into();
+    full.build().unwrap();
+
+    for page in &["chapter.html", "index.html"] {
+        assert_eq!(
+            main_content(&read(&html_dir.join(page))),
+            main_content(&read(&full_dir.path().join(page))),
+            "{} doesn't match a full build",
+            page
+        );
+    }
+    assert_eq!(
+        main_content(&read(&html_dir.join("print.html"))),
+        main_content(&read(&full_dir.path().join("print.html")))
+    );
+
+    // changing our summary changes every page's navigation
+    fs::write(
+        book.path().join("src/SUMMARY.md"),
+        "# Summary\n\n- [Chapter](chapter.md)\n- [Renamed](other.md)\n",
+    )
+    .unwrap();
+    assert_eq!(ws_next_text(&mut websocket).unwrap(), "reload");
+
+    assert_ne!(modified(&html_dir.join("other.html")), other_built);
+    assert!(read(&html_dir.join("chapter.html")).contains("Renamed"));
+}
@@ -0,0 +1,268 @@
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+
+use mdnotes::{BuildOutput, EnvironmentSetup, MdNotesRuntime, PortPolicy};
+
+use crate::common::{
+    http_get, output_runtime, with_every_output, write_book, write_book_named, ws_next_text,
+    ServedBook,
+};
+
+mod common;
+
+fn runtime_with_port(port: PortPolicy) -> MdNotesRuntime {
+    MdNotesRuntime::builder()
+        .port(port)
+        .environment(EnvironmentSetup::Skip)
+        .build_output(BuildOutput::Memory)
+        .build()
+        .unwrap()
+}
+
+fn random_port_runtime() -> MdNotesRuntime {
+    runtime_with_port(PortPolicy::Random)
+}
+
+#[test]
+fn notes_are_mounted_at_their_slug_and_id() {
+    let first = write_book("[book]\ntitle = \"My Notes!\"\n");
+    let second = write_book("[book]\ntitle = \"my notes\"\n");
+    let numeric = write_book("[book]\ntitle = \"2020\"\n");
+    let untitled = write_book_named("Untitled Book ", "[book]\n");
+    fs::write(second.path().join("src/chapter.md"), "# Second\n").unwrap();
+
+    let runtime = random_port_runtime();
+    let port = runtime.server_port();
+
+    let first_id = runtime.open_notes(first.path().into()).unwrap();
+    let second_id = runtime.open_notes(second.path().into()).unwrap();
+    let numeric_id = runtime.open_notes(numeric.path().into()).unwrap();
+    let untitled_id = runtime.open_notes(untitled.path().into()).unwrap();
+
+    assert_eq!(runtime.notes_slug(first_id).unwrap(), "my-notes");
+    assert_eq!(runtime.notes_slug(second_id).unwrap(), "my-notes-2");
+    assert_eq!(runtime.notes_slug(numeric_id).unwrap(), "notes-2020");
+    assert!(runtime
+        .notes_slug(untitled_id)
+        .unwrap()
+        .starts_with("untitled-book-"));
+
+    let by_slug = http_get(port, "/my-notes-2/static/chapter.html", &[]);
+    assert_eq!(by_slug.status, 200);
+    assert!(String::from_utf8_lossy(&by_slug.body).contains("Second"));
+    assert!(String::from_utf8_lossy(&by_slug.body).contains("/my-notes-2/ws"));
+
+    // the numeric id is an alias for the same notes
+    let by_id = http_get(port, &format!("/{}/static/chapter.html", second_id), &[]);
+    assert_eq!(by_id.status, 200);
+    assert_eq!(by_id.body, by_slug.body);
+
+    assert_eq!(http_get(port, "/unknown/static/", &[]).status, 404);
+
+    // closed notes free up their slug
+    runtime.close_notes(first_id).unwrap();
+    assert_eq!(http_get(port, "/my-notes/static/", &[]).status, 404);
+    let reopened_id = runtime.open_notes(first.path().into()).unwrap();
+    assert_eq!(runtime.notes_slug(reopened_id).unwrap(), "my-notes");
+}
+
+#[test]
+fn preferred_port_falls_back_when_taken() {
+    let free_port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let first = runtime_with_port(PortPolicy::Preferred(free_port));
+    assert_eq!(first.server_port(), free_port);
+
+    let second = runtime_with_port(PortPolicy::Preferred(free_port));
+    assert_ne!(second.server_port(), free_port);
+    assert_ne!(second.server_port(), 0);
+}
+
+#[test]
+fn persisted_port_is_saved_and_reused() {
+    let state_dir = tempfile::tempdir().unwrap();
+    let port_file = state_dir.path().join("nested/server-port");
+    let policy = PortPolicy::Persisted(port_file.clone());
+
+    let first = runtime_with_port(policy.clone());
+    assert_eq!(first.config().port, policy);
+    assert_eq!(
+        fs::read_to_string(&port_file).unwrap(),
+        first.server_port().to_string()
+    );
+
+    // our saved port is taken for now, so we pick another without giving up on ours
+    let second = runtime_with_port(policy.clone());
+    assert_ne!(second.server_port(), first.server_port());
+    assert_eq!(policy.preferred_port(), Some(first.server_port()));
+
+    // a file without a port gets one
+    fs::write(&port_file, "not a port").unwrap();
+    let third = runtime_with_port(policy.clone());
+    assert_eq!(policy.preferred_port(), Some(third.server_port()));
+}
+
+#[test]
+fn every_consumer_persists_its_own_port() {
+    assert_eq!(PortPolicy::default(), PortPolicy::persisted_for("app"));
+    assert_ne!(
+        PortPolicy::persisted_for("app"),
+        PortPolicy::persisted_for("cli")
+    );
+}
+
+#[test]
+fn builder_binds_to_the_configured_address() {
+    let book = write_book("[book]\ntitle = \"Anywhere\"\n");
+    let runtime = MdNotesRuntime::builder()
+        .bind_address([0, 0, 0, 0])
+        .port(PortPolicy::Random)
+        .environment(EnvironmentSetup::Skip)
+        .init_logger(false)
+        .build()
+        .unwrap();
+
+    assert_eq!(
+        runtime.config().bind_address.to_string(),
+        "0.0.0.0".to_string()
+    );
+
+    let notes_id = runtime.open_notes(book.path().into()).unwrap();
+    let chapter = http_get(runtime.server_port(), "/anywhere/static/chapter.html", &[]);
+    assert_eq!(chapter.status, 200);
+    // viewers on this machine still connect through localhost
+    assert!(String::from_utf8_lossy(&chapter.body).contains(&format!(
+        "ws://localhost:{}/anywhere/ws",
+        runtime.server_port()
+    )));
+
+    runtime.close_notes(notes_id).unwrap();
+}
+
+#[test]
+fn failed_builds_show_an_overlay_until_fixed() {
+    with_every_output(check_overlay);
+}
+
+fn check_overlay(build_output: BuildOutput) {
+    let book = write_book("[book]\ntitle = \"Overlay\"\n[build]\ncreate-missing = false\n");
+    let served = ServedBook::open(book.path(), build_output);
+    let overlay = "id=\"mdnotes-build-error\"";
+    let shows_overlay = |file: &str| {
+        let page = served.get(file, &[]);
+        String::from_utf8_lossy(&page.body).contains(overlay)
+    };
+
+    let mut websocket = served.websocket();
+
+    assert!(!shows_overlay("chapter.html"));
+
+    fs::write(
+        book.path().join("src/SUMMARY.md"),
+        "# Summary\n\n- [Gone](gone.md)\n",
+    )
+    .unwrap();
+    assert_eq!(ws_next_text(&mut websocket).unwrap(), "reload");
+
+    // our old output is still served, with the failure on top of it
+    let page = served.get("chapter.html", &[]);
+    let html = String::from_utf8_lossy(&page.body);
+    assert_eq!(page.status, 200);
+    assert!(html.contains(overlay));
+    assert!(html.contains("gone.md"));
+    assert_eq!(page.header("cache-control"), Some("no-store"));
+
+    // pages our last good build never made still explain what happened, but non-html files
+    // are untouched
+    assert!(shows_overlay("gone.html"));
+    assert!(!shows_overlay("css/general.css"));
+
+    fs::write(
+        book.path().join("src/SUMMARY.md"),
+        "# Summary\n\n- [Chapter](chapter.md)\n",
+    )
+    .unwrap();
+    assert_eq!(ws_next_text(&mut websocket).unwrap(), "reload");
+
+    assert!(!shows_overlay("chapter.html"));
+}
+
+#[test]
+fn books_are_only_built_into_their_own_directory_when_asked() {
+    let cache_dir = tempfile::tempdir().unwrap();
+
+    let book = write_book("[book]\ntitle = \"Cached\"\n");
+    let runtime = output_runtime(BuildOutput::Cache(cache_dir.path().into()));
+    runtime.open_notes(book.path().into()).unwrap();
+    assert!(!book.path().join("book").exists());
+    let built: Vec<_> = fs::read_dir(cache_dir.path()).unwrap().collect();
+    assert_eq!(built.len(), 1);
+    assert!(built[0]
+        .as_ref()
+        .unwrap()
+        .path()
+        .join("chapter.html")
+        .exists());
+
+    let book = write_book("[book]\ntitle = \"In Book\"\n");
+    let runtime = output_runtime(BuildOutput::Book);
+    runtime.open_notes(book.path().into()).unwrap();
+    assert!(book.path().join("book/chapter.html").exists());
+}
+
+#[test]
+fn cache_directories_stay_put_across_releases() {
+    // the same name for the same book no matter which Rust built us
+    assert_eq!(
+        BuildOutput::Cache("/cache".into()).build_dir(1, Path::new("/books/notes")),
+        Some(PathBuf::from("/cache/notes-5ed16bed705ae5d2"))
+    );
+}
+
+#[test]
+fn memory_output_is_served_without_touching_the_disk() {
+    let book = write_book("[book]\ntitle = \"Memory\"\n");
+    fs::create_dir(book.path().join("src/nested")).unwrap();
+    fs::write(book.path().join("src/nested/index.md"), "# Nested\n").unwrap();
+    fs::write(
+        book.path().join("src/SUMMARY.md"),
+        "# Summary\n\n- [Chapter](chapter.md)\n- [Nested](nested/index.md)\n",
+    )
+    .unwrap();
+    let served = ServedBook::open(book.path(), BuildOutput::Memory);
+    assert!(!book.path().join("book").exists());
+
+    let mut websocket = served.websocket();
+
+    // the file we ask for and the status and content type we get back
+    let files = &[
+        ("", 200, Some("text/html")),
+        ("css/general.css", 200, Some("text/css")),
+        // directories are served by their index, if they have one
+        ("nested", 200, Some("text/html")),
+        ("css", 404, None),
+        ("missing.html", 404, None),
+        ("../book.toml", 404, None),
+    ];
+    for (file, status, content_type) in files {
+        let response = served.get(file, &[]);
+        assert_eq!(response.status, *status, "{}", file);
+        if content_type.is_some() {
+            assert_eq!(response.header("content-type"), *content_type, "{}", file);
+        }
+    }
+    let index = served.get("", &[]);
+    assert!(String::from_utf8_lossy(&index.body).contains("Chapter"));
+
+    fs::write(book.path().join("src/chapter.md"), "# Rewritten\n").unwrap();
+    assert_eq!(ws_next_text(&mut websocket).unwrap(), "reload");
+
+    let chapter = served.get("chapter.html", &[]);
+    assert!(String::from_utf8_lossy(&chapter.body).contains("Rewritten"));
+    assert!(!book.path().join("book").exists());
+}
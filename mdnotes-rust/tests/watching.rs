@@ -0,0 +1,198 @@
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use mdnotes::{BuildOutput, EnvironmentSetup, MdNotesRuntime, NotesEvent, PortPolicy, WatchMode};
+
+use crate::common::{http_get, write_book, ws_connect, ws_next_text};
+
+mod common;
+
+fn next_json(websocket: &mut tungstenite::WebSocket<std::net::TcpStream>) -> Value {
+    serde_json::from_str(&ws_next_text(websocket).unwrap()).unwrap()
+}
+
+/// Wait for the next build and return the paths that set it off
+fn next_build(websocket: &mut tungstenite::WebSocket<std::net::TcpStream>) -> Vec<String> {
+    assert_eq!(next_json(websocket)["type"], "build-started");
+    let reload = next_json(websocket);
+    assert_eq!(reload["type"], "reload", "{}", reload);
+
+    reload["changes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|change| change["path"].as_str().unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn files_outside_of_our_source_trigger_builds() {
+    let book = write_book(
+        "[book]\ntitle = \"Outside\"\n\n[output.html]\nadditional-css = [\"custom.css\"]\n",
+    );
+    fs::write(book.path().join("custom.css"), "body {}\n").unwrap();
+    fs::create_dir(book.path().join("snippets")).unwrap();
+    fs::write(book.path().join("snippets/code.rs"), "fn first() {}\n").unwrap();
+    fs::write(
+        book.path().join("src/chapter.md"),
+        "# Chapter\n\n```rust\n{{#include ../snippets/code.rs}}\n```\n",
+    )
+    .unwrap();
+
+    let runtime = MdNotesRuntime::builder()
+        .port(PortPolicy::Random)
+        .environment(EnvironmentSetup::Skip)
+        .build_output(BuildOutput::Memory)
+        .build()
+        .unwrap();
+    let port = runtime.server_port();
+    runtime.open_notes(book.path().into()).unwrap();
+
+    let mut websocket = ws_connect(port, "/outside/ws?protocol=json");
+
+    fs::write(book.path().join("snippets/code.rs"), "fn second() {}\n").unwrap();
+    assert_eq!(next_build(&mut websocket), vec!["snippets/code.rs"]);
+    let response = http_get(port, "/outside/static/chapter.html", &[]);
+    assert!(String::from_utf8_lossy(&response.body).contains("second"));
+
+    fs::write(book.path().join("custom.css"), "body { margin: 0 }\n").unwrap();
+    assert_eq!(next_build(&mut websocket), vec!["custom.css"]);
+
+    // new files in book.toml are watched from the next build on
+    fs::write(book.path().join("custom.js"), "// nothing yet\n").unwrap();
+    fs::write(
+        book.path().join("book.toml"),
+        "[book]\ntitle = \"Outside\"\n\n[output.html]\nadditional-css = [\"custom.css\"]\n\
+         additional-js = [\"custom.js\"]\n",
+    )
+    .unwrap();
+    assert_eq!(next_build(&mut websocket), vec!["book.toml"]);
+
+    fs::write(book.path().join("custom.js"), "console.log('hi');\n").unwrap();
+    assert_eq!(next_build(&mut websocket), vec!["custom.js"]);
+
+    // includes added to a chapter are watched once it's been rebuilt
+    fs::write(book.path().join("snippets/more.rs"), "fn third() {}\n").unwrap();
+    fs::write(
+        book.path().join("src/chapter.md"),
+        "# Chapter\n\n```rust\n{{#include ../snippets/more.rs}}\n```\n",
+    )
+    .unwrap();
+    assert_eq!(next_build(&mut websocket), vec!["src/chapter.md"]);
+
+    fs::write(book.path().join("snippets/more.rs"), "fn fourth() {}\n").unwrap();
+    assert_eq!(next_build(&mut websocket), vec!["snippets/more.rs"]);
+    let response = http_get(port, "/outside/static/chapter.html", &[]);
+    assert!(String::from_utf8_lossy(&response.body).contains("fourth"));
+}
+
+#[test]
+fn moving_our_sources_moves_our_watches() {
+    let book = write_book("[book]\ntitle = \"Moving\"\n");
+    fs::create_dir(book.path().join("notes")).unwrap();
+    fs::write(
+        book.path().join("notes/SUMMARY.md"),
+        "# Summary\n\n- [Page](page.md)\n",
+    )
+    .unwrap();
+    fs::write(book.path().join("notes/page.md"), "# Page\n").unwrap();
+
+    let runtime = MdNotesRuntime::builder()
+        .port(PortPolicy::Random)
+        .environment(EnvironmentSetup::Skip)
+        .build_output(BuildOutput::Memory)
+        .build()
+        .unwrap();
+    let port = runtime.server_port();
+    runtime.open_notes(book.path().into()).unwrap();
+
+    let mut websocket = ws_connect(port, "/moving/ws?protocol=json");
+
+    fs::write(
+        book.path().join("book.toml"),
+        "[book]\ntitle = \"Moving\"\nsrc = \"notes\"\n",
+    )
+    .unwrap();
+    assert_eq!(next_json(&mut websocket)["type"], "build-started");
+    assert_eq!(next_json(&mut websocket)["type"], "full-reload");
+
+    // our old sources don't matter anymore
+    fs::write(book.path().join("src/chapter.md"), "# Forgotten\n").unwrap();
+    thread::sleep(Duration::from_millis(500));
+    fs::write(book.path().join("notes/page.md"), "# Moved Page\n").unwrap();
+
+    assert_eq!(next_json(&mut websocket)["type"], "build-started");
+    let reload = next_json(&mut websocket);
+    assert_eq!(
+        reload["changes"],
+        json!([{"path": "notes/page.md", "url": "/moving/static/page.html"}])
+    );
+    let response = http_get(port, "/moving/static/page.html", &[]);
+    assert!(String::from_utf8_lossy(&response.body).contains("Moved Page"));
+}
+
+#[test]
+fn removed_books_are_orphaned() {
+    let book = write_book("[book]\ntitle = \"Removed\"\n");
+    let runtime = MdNotesRuntime::builder()
+        .port(PortPolicy::Random)
+        .environment(EnvironmentSetup::Skip)
+        .build_output(BuildOutput::Memory)
+        .build()
+        .unwrap();
+    let port = runtime.server_port();
+    let notes_id = runtime.open_notes(book.path().into()).unwrap();
+
+    let events = Arc::new(Mutex::new(vec![]));
+    let listener_events = events.clone();
+    runtime.set_event_listener(move |_, event| {
+        listener_events.lock().unwrap().push(event.clone());
+    });
+
+    let mut websocket = ws_connect(port, "/removed/ws?protocol=json");
+    assert_eq!(runtime.notes_orphaned(notes_id), Some(false));
+
+    fs::remove_dir_all(book.path()).unwrap();
+
+    assert_eq!(next_json(&mut websocket)["type"], "notebook-orphaned");
+    assert_eq!(runtime.notes_orphaned(notes_id), Some(true));
+    assert!(events.lock().unwrap().contains(&NotesEvent::Orphaned));
+
+    // our last build is still served until we're closed
+    let response = http_get(port, "/removed/static/chapter.html", &[]);
+    assert_eq!(response.status, 200);
+
+    runtime.close_notes(notes_id).unwrap();
+    assert_eq!(runtime.notes_orphaned(notes_id), None);
+}
+
+#[test]
+fn polling_sees_what_file_events_would() {
+    let book = write_book("[book]\ntitle = \"Polled\"\n");
+    fs::write(book.path().join("src/extra.md"), "# Extra\n").unwrap();
+
+    let runtime = MdNotesRuntime::builder()
+        .port(PortPolicy::Random)
+        .environment(EnvironmentSetup::Skip)
+        .build_output(BuildOutput::Memory)
+        .watch_mode(WatchMode::Polling)
+        .poll_interval(Duration::from_millis(100))
+        .build()
+        .unwrap();
+    let port = runtime.server_port();
+    runtime.open_notes(book.path().into()).unwrap();
+
+    let mut websocket = ws_connect(port, "/polled/ws?protocol=json");
+
+    fs::write(book.path().join("src/chapter.md"), "# Polled Chapter\n").unwrap();
+    assert_eq!(next_build(&mut websocket), vec!["src/chapter.md"]);
+    let response = http_get(port, "/polled/static/chapter.html", &[]);
+    assert!(String::from_utf8_lossy(&response.body).contains("Polled Chapter"));
+
+    fs::remove_file(book.path().join("src/extra.md")).unwrap();
+    assert_eq!(next_build(&mut websocket), vec!["src/extra.md"]);
+}
@@ -0,0 +1,97 @@
+use std::fs;
+
+use serde_json::{json, Value};
+
+use mdnotes::{BuildOutput, EnvironmentSetup, MdNotesRuntime, PortPolicy};
+
+use crate::common::{write_book, ws_connect, ws_next_text, ServedBook};
+
+mod common;
+
+fn next_json(websocket: &mut tungstenite::WebSocket<std::net::TcpStream>) -> Value {
+    serde_json::from_str(&ws_next_text(websocket).unwrap()).unwrap()
+}
+
+#[test]
+fn clients_get_json_or_stock_livereload_messages() {
+    let book = write_book("[book]\ntitle = \"Protocol\"\n");
+    let runtime = MdNotesRuntime::builder()
+        .port(PortPolicy::Random)
+        .environment(EnvironmentSetup::Skip)
+        .build_output(BuildOutput::Memory)
+        .build()
+        .unwrap();
+    let port = runtime.server_port();
+    let notes_id = runtime.open_notes(book.path().into()).unwrap();
+
+    let mut json_client = ws_connect(port, "/protocol/ws?protocol=json");
+    let mut stock_client = ws_connect(port, "/protocol/ws");
+
+    fs::write(book.path().join("src/chapter.md"), "# Changed\n").unwrap();
+
+    assert_eq!(
+        next_json(&mut json_client),
+        json!({"version": 1, "type": "build-started"})
+    );
+    assert_eq!(
+        next_json(&mut json_client),
+        json!({
+            "version": 1,
+            "type": "reload",
+            "changes": [{"path": "src/chapter.md", "url": "/protocol/static/chapter.html"}],
+        })
+    );
+    assert_eq!(ws_next_text(&mut stock_client).unwrap(), "reload");
+
+    // break our book so that the next build fails
+    fs::write(book.path().join("book.toml"), "[book\n").unwrap();
+
+    assert_eq!(next_json(&mut json_client)["type"], "build-started");
+    let failure = next_json(&mut json_client);
+    assert_eq!(failure["type"], "build-failed");
+    assert!(failure["diagnostics"][0]["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("Couldn't load the book"));
+    assert_eq!(failure["diagnostics"][0]["file"], "book.toml");
+    assert_eq!(ws_next_text(&mut stock_client).unwrap(), "reload");
+
+    runtime.close_notes(notes_id).unwrap();
+
+    assert_eq!(
+        next_json(&mut json_client),
+        json!({"version": 1, "type": "notebook-closed"})
+    );
+    assert_eq!(ws_next_text(&mut json_client), None);
+    // the stock script doesn't know about closing, it just sees the socket go away
+    assert_eq!(ws_next_text(&mut stock_client), None);
+}
+
+#[test]
+fn failures_name_the_file_mdbook_failed_on() {
+    let book = write_book("[book]\ntitle = \"Failing\"\n[build]\ncreate-missing = false\n");
+    let served = ServedBook::open(book.path(), BuildOutput::Memory);
+    let mut client = ws_connect(served.port, &format!("/{}/ws?protocol=json", served.slug));
+    // builds that were already queued before our change can still come first
+    let mut next_failure = || loop {
+        let message = next_json(&mut client);
+        if message["type"] == "build-failed" {
+            return message["diagnostics"][0].clone();
+        }
+    };
+
+    fs::write(
+        book.path().join("src/SUMMARY.md"),
+        "# Summary\n\n- [Gone](gone.md)\n",
+    )
+    .unwrap();
+    assert_eq!(next_failure()["file"], "src/gone.md");
+
+    // a failure that doesn't name a file doesn't blame the one we just changed
+    fs::write(
+        book.path().join("book.toml"),
+        "[book]\ntitle = \"Failing\"\n[preprocessor.silent]\ncommand = \"true\"\n",
+    )
+    .unwrap();
+    assert_eq!(next_failure()["file"], Value::Null);
+}
@@ -12,7 +12,7 @@ import SwiftUI
 @NSApplicationMain
 class AppDelegate: NSObject, NSApplicationDelegate {
 
-    var notesWindows: [NSWindowController: UInt8] = [NSWindowController: UInt8]()
+    var notesWindows: [NSWindowController: UInt64] = [NSWindowController: UInt64]()
 
     private let runtime: MdNotesRuntime = MdNotesRuntime.shared
 
@@ -58,12 +58,19 @@ class AppDelegate: NSObject, NSApplicationDelegate {
 
             // TODO this threading probably does nothing
             DispatchQueue.main.async {
-                let note_id = self.runtime.openNotes(path: path)
+                let note_id: UInt64
+                do {
+                    note_id = try self.runtime.openNotes(path: path)
+                } catch {
+                    window.close()
+                    NSAlert(error: error).runModal()
+                    return
+                }
 
                 //print(path?.absoluteString)
                 let serverPort = self.runtime.serverPort()
                 let serverBaseUrl = URL(string: "http://localhost:" + String(serverPort))!
-                let serverUrl = serverBaseUrl.appendingPathComponent(String(note_id))
+                let serverUrl = serverBaseUrl.appendingPathComponent(self.runtime.notesSlug(id: note_id))
                         .appendingPathComponent("static", isDirectory: true)
 
                 // Create the window and set the content view.
@@ -9,6 +9,21 @@
 import Foundation
 import Swift
 
+struct MdNotesError: Error, LocalizedError {
+    let status: md_notes_status
+    let message: String
+
+    var errorDescription: String? {
+        message
+    }
+
+    static func last() -> MdNotesError {
+        let message = md_notes_last_error_message().map { String(cString: $0) } ?? "Unknown error"
+
+        return MdNotesError(status: md_notes_last_error_status(), message: message)
+    }
+}
+
 class MdNotesRuntime {
 
     static let shared = MdNotesRuntime()
@@ -19,16 +34,34 @@ class MdNotesRuntime {
         rust = md_notes_runtime_new()
     }
     
-    func openNotes(path: String) -> UInt8 {
+    func openNotes(path: String) throws -> UInt64 {
         let raw_path = (path as NSString).utf8String
 
-        return md_notes_runtime_open_notes(rust, raw_path)
+        var id: UInt64 = 0
+        if md_notes_runtime_open_notes_v2(rust, raw_path, &id) != MD_NOTES_STATUS_OK {
+            throw MdNotesError.last()
+        }
+
+        return id
     }
 
-    func closeNotes(id: UInt8) {
-        md_notes_runtime_close_notes(rust, id)
+    func closeNotes(id: UInt64) {
+        if md_notes_runtime_close_notes_v2(rust, id) != MD_NOTES_STATUS_OK {
+            print(MdNotesError.last().message)
+        }
     }
     
+    func notesSlug(id: UInt64) -> String {
+        guard let raw_slug = md_notes_runtime_notes_slug(rust, id) else {
+            return String(id)
+        }
+        defer {
+            md_notes_string_free(raw_slug)
+        }
+
+        return String(cString: raw_slug)
+    }
+
     func serverPort() -> UInt16 {
         md_notes_runtime_server_port(rust)
     }
@@ -3,16 +3,118 @@
 //
 #pragma once
 
+#include <stdbool.h>
+#include <stddef.h>
 #include <stdint.h>
 
 typedef struct md_notes_runtime md_notes_runtime;
 
+typedef enum md_notes_status {
+    MD_NOTES_STATUS_OK = 0,
+    MD_NOTES_STATUS_INVALID_ARGUMENT = 1,
+    MD_NOTES_STATUS_NULL_POINTER = 2,
+    MD_NOTES_STATUS_PANIC = 3,
+    MD_NOTES_STATUS_IO = 4,
+    MD_NOTES_STATUS_CONFIG = 5,
+    MD_NOTES_STATUS_BUILD = 6,
+    MD_NOTES_STATUS_WATCH = 7,
+    MD_NOTES_STATUS_SERVER = 8,
+    MD_NOTES_STATUS_INVALID_NOTES_ID = 9,
+} md_notes_status;
+
+md_notes_status md_notes_last_error_status(void);
+
+const char* md_notes_last_error_message(void);
+
+void md_notes_clear_last_error(void);
+
 md_notes_runtime* md_notes_runtime_new(void);
 
+typedef enum md_notes_port_policy {
+    MD_NOTES_PORT_RANDOM = 0,
+    MD_NOTES_PORT_PREFERRED = 1,
+    MD_NOTES_PORT_PERSISTED = 2,
+} md_notes_port_policy;
+
+typedef enum md_notes_build_output {
+    MD_NOTES_BUILD_OUTPUT_BOOK = 0,
+    MD_NOTES_BUILD_OUTPUT_CACHE = 1,
+    MD_NOTES_BUILD_OUTPUT_MEMORY = 2,
+} md_notes_build_output;
+
+typedef enum md_notes_watch_mode {
+    MD_NOTES_WATCH_AUTO = 0,
+    MD_NOTES_WATCH_NATIVE = 1,
+    MD_NOTES_WATCH_POLLING = 2,
+} md_notes_watch_mode;
+
+// Start from md_notes_runtime_options_default(), null strings use their defaults
+typedef struct md_notes_runtime_options {
+    const char* bind_address;
+    md_notes_port_policy port_policy;
+    uint16_t port;
+    const char* port_file;
+    bool init_logger;
+    bool skip_environment;
+    const char* const* shells;
+    size_t shells_len;
+    size_t broadcast_buffer;
+    md_notes_build_output build_output;
+    const char* cache_dir;
+    size_t max_concurrent_builds;
+    // null builds in process, otherwise each build runs in `<build_worker> build-worker`
+    const char* build_worker;
+    uint64_t build_timeout_ms;
+    md_notes_watch_mode watch_mode;
+    uint64_t poll_interval_ms;
+} md_notes_runtime_options;
+
+md_notes_runtime_options md_notes_runtime_options_default(void);
+
+md_notes_runtime* md_notes_runtime_new_with_options(const md_notes_runtime_options*);
+
+// Call from main when started with `build-worker`, returns the exit code, or
+// MD_NOTES_STATUS_PANIC with the last error set if the build panicked
+int32_t md_notes_run_build_worker(void);
+
 void md_notes_runtime_free(md_notes_runtime*);
 
 uint16_t md_notes_runtime_server_port(md_notes_runtime*);
 
 uint8_t md_notes_runtime_open_notes(md_notes_runtime*, const char *);
 
-void md_notes_runtime_close_notes(md_notes_runtime*, uint8_t);
+md_notes_status md_notes_runtime_close_notes(md_notes_runtime*, uint8_t);
+
+// Version 2 of the interface uses full width notes ids
+
+uint32_t md_notes_api_version(void);
+
+md_notes_status md_notes_runtime_open_notes_v2(md_notes_runtime*, const char *, uint64_t* notes_id);
+
+md_notes_status md_notes_runtime_close_notes_v2(md_notes_runtime*, uint64_t);
+
+char* md_notes_runtime_notes_slug(md_notes_runtime*, uint64_t);
+
+void md_notes_string_free(char*);
+
+typedef enum md_notes_event_kind {
+    MD_NOTES_EVENT_LOADING = 0,
+    MD_NOTES_EVENT_BUILT = 1,
+    MD_NOTES_EVENT_BUILD_FAILED = 2,
+    MD_NOTES_EVENT_CLOSED = 3,
+    MD_NOTES_EVENT_WATCH_FAILED = 4,
+    MD_NOTES_EVENT_ORPHANED = 5,
+} md_notes_event_kind;
+
+typedef struct md_notes_event {
+    md_notes_event_kind kind;
+    uint64_t notes_id;
+    uint64_t duration_ms;
+    const char* message;
+} md_notes_event;
+
+typedef void (*md_notes_event_callback)(void* context, const md_notes_event* event);
+
+// Once this returns, or md_notes_runtime_free does, the previous callback has finished running
+// and its context can be freed
+md_notes_status md_notes_runtime_set_event_callback(md_notes_runtime*, md_notes_event_callback, void* context);